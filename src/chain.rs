@@ -0,0 +1,411 @@
+//! Filters made of several stages that run in order under one attribute.
+
+use crate::stats::{self, FailureKind};
+use crate::{
+    trace, Driver, InProcessFilter, ProcessFilter, ProcessFilterRegistration, DEFAULT_TIMEOUT,
+};
+use git2::{Error, FilterMode, Repository};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A pipeline of filters registered under a single `filter=<name>` attribute.
+///
+/// Stages are listed in clean order, the way content flows into the object
+/// database. Smudge runs them in reverse. Each stage is an external command,
+/// an in-process Rust filter, or a filter configured in git config (which may
+/// use a long-running process).
+///
+/// Consecutive external commands are connected with OS pipes, so content
+/// streams between them without being buffered here. If a stage fails, the
+/// error names the chain and the stage.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::{FilterMode, Repository};
+/// use git2_process_filter::FilterChain;
+///
+/// let repo = Repository::open(".")?;
+///
+/// // smudge: decrypt, then decompress, then normalize; clean: the reverse
+/// let _reg = FilterChain::new("secure")
+///     .in_process_stage("normalize", |mode: FilterMode, _path: &str, input: &[u8]| {
+///         match mode {
+///             FilterMode::ToOdb => Ok(input.iter().copied().filter(|&b| b != b'\r').collect()),
+///             FilterMode::ToWorktree => Ok(input.to_vec()),
+///         }
+///     })
+///     .command_stage("compress", "gzip -cn", "gzip -cd")
+///     .config_stage(&repo, "crypt")?
+///     .register()?;
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct FilterChain {
+    name: String,
+    stages: Vec<ProcessFilter>,
+}
+
+impl FilterChain {
+    /// Start an empty chain for `filter=<name>`. With no stages, content
+    /// passes through unchanged.
+    pub fn new(name: &str) -> Self {
+        FilterChain {
+            name: name.to_string(),
+            stages: Vec::new(),
+        }
+    }
+
+    /// Add a stage that runs external commands, as with
+    /// [`register_process_filter_with_commands`](crate::register_process_filter_with_commands).
+    /// An empty command passes content through in that direction.
+    pub fn command_stage(mut self, stage: &str, clean_cmd: &str, smudge_cmd: &str) -> Self {
+        self.stages.push(ProcessFilter::new(
+            stage,
+            Driver::Commands {
+                clean: clean_cmd.to_string(),
+                smudge: smudge_cmd.to_string(),
+            },
+        ));
+        self
+    }
+
+    /// Add a stage implemented in Rust, as with
+    /// [`register_in_process_filter`](crate::register_in_process_filter). Its
+    /// errors fail the chain.
+    pub fn in_process_stage<F: InProcessFilter>(mut self, stage: &str, filter: F) -> Self {
+        self.stages.push(ProcessFilter {
+            required: true,
+            ..ProcessFilter::new(stage, Driver::InProcess(Box::new(filter)))
+        });
+        self
+    }
+
+    /// Add a stage configured by `filter.<name>.*` in the repository's config,
+    /// as with [`register_process_filter`](crate::register_process_filter).
+    pub fn config_stage(mut self, repo: &Repository, name: &str) -> Result<Self, Error> {
+        self.stages.push(ProcessFilter::from_config(repo, name)?);
+        Ok(self)
+    }
+
+    /// Register the chain. It remains active until the returned handle is dropped.
+    pub fn register(self) -> Result<ProcessFilterRegistration, Error> {
+        ProcessFilterRegistration::register(ProcessFilter::new(
+            &self.name,
+            Driver::Chain(self.stages),
+        ))
+    }
+}
+
+/// Run `stages` of chain `chain` over one blob.
+pub(crate) fn run(
+    chain: &str,
+    stages: &[ProcessFilter],
+    mode: FilterMode,
+    path: &str,
+    workdir: Option<&Path>,
+    input: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut order: Vec<(usize, &ProcessFilter)> = stages.iter().enumerate().collect();
+    if mode == FilterMode::ToWorktree {
+        order.reverse();
+    }
+    let stage_error = |index: usize, stage: &ProcessFilter, e: &Error| {
+        Error::from_str(&format!(
+            "filter '{}' stage {}/{} '{}' failed to {}: {}",
+            chain,
+            index + 1,
+            stages.len(),
+            stage.name,
+            direction(mode),
+            e.message()
+        ))
+    };
+
+    let mut data = Cow::Borrowed(input);
+    let mut rest = &order[..];
+    while let Some(&(index, stage)) = rest.first() {
+        let piped = rest
+            .iter()
+            .take_while(|(_, s)| s.command(mode).is_some())
+            .count();
+        if piped > 1 {
+            // Connect consecutive external commands directly
+            let (pipeline, tail) = rest.split_at(piped);
+            data = Cow::Owned(
+                run_pipeline(pipeline, mode, path, workdir, &data)
+                    .map_err(|(index, e)| stage_error(index, &stages[index], &e))?,
+            );
+            rest = tail;
+        } else {
+            data = Cow::Owned(
+                stage
+                    .filter(mode, path, workdir, &data)
+                    .map_err(|e| stage_error(index, stage, &e))?,
+            );
+            rest = &rest[1..];
+        }
+    }
+    Ok(data.into_owned())
+}
+
+fn direction(mode: FilterMode) -> &'static str {
+    match mode {
+        FilterMode::ToOdb => "clean",
+        FilterMode::ToWorktree => "smudge",
+    }
+}
+
+/// A spawned pipeline stage and the thread collecting its stderr.
+struct Running {
+    index: usize,
+    program: String,
+    child: Child,
+    stderr: thread::JoinHandle<Vec<u8>>,
+}
+
+/// Run external commands as one OS pipeline, each reading the previous one's
+/// stdout. On failure, returns the index of the stage to blame.
+fn run_pipeline(
+    pipeline: &[(usize, &ProcessFilter)],
+    mode: FilterMode,
+    path: &str,
+    workdir: Option<&Path>,
+    input: &[u8],
+) -> Result<Vec<u8>, (usize, Error)> {
+    let mut running: Vec<Running> = Vec::new();
+    let mut previous = None;
+
+    for &(index, stage) in pipeline {
+        let cmd = stage.command(mode).unwrap_or_default();
+        let (program, args) = ProcessFilter::parse_command(cmd, path);
+        let mut command = Command::new(&program);
+        command
+            .args(&args)
+            .stdin(previous.take().map_or_else(Stdio::piped, Stdio::from))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = workdir {
+            command.current_dir(dir);
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                stats::note_failure(FailureKind::Spawn);
+                for mut r in running {
+                    let _ = r.child.kill();
+                    let _ = r.child.wait();
+                }
+                let message = format!("failed to spawn '{}': {}", program, e);
+                return Err((index, Error::from_str(&message)));
+            }
+        };
+        stats::note_spawn();
+        trace::event(|| format!("spawned {:?} {:?} (pid {})", program, args, child.id()));
+
+        previous = child.stdout.take();
+        let mut stderr = child.stderr.take();
+        let stderr = thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Some(stderr) = &mut stderr {
+                let _ = stderr.read_to_end(&mut data);
+            }
+            data
+        });
+        running.push(Running {
+            index,
+            program,
+            child,
+            stderr,
+        });
+    }
+
+    let start = Instant::now();
+    let mut stdin = running[0].child.stdin.take();
+    let mut output = Vec::new();
+    let (written, read, timed_out) = thread::scope(|s| {
+        // Write and read in separate threads to avoid deadlock, and so a hung
+        // stage can be killed from here
+        let writer = s.spawn(move || {
+            let result = stdin.as_mut().map_or(Ok(()), |w| w.write_all(input));
+            drop(stdin); // Close stdin to signal EOF
+            result
+        });
+        let output = &mut output;
+        let reader = s.spawn(move || previous.as_mut().map_or(Ok(0), |r| r.read_to_end(output)));
+        // Killing the stages closes their pipes, which unblocks both threads
+        let timed_out = wait_or_kill(&mut running, start);
+        let read = reader
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("read thread panicked")));
+        (writer.join(), read, timed_out)
+    });
+
+    // Exit statuses explain a broken pipe better than the pipe error does, so
+    // check them first. Blame the last stage that failed: when a stage exits
+    // early, the stages before it die writing into the closed pipe
+    let killed = timed_out.is_some();
+    let mut failure = timed_out.map(|(index, program)| {
+        stats::note_failure(FailureKind::Timeout);
+        let message = format!("'{}' timed out after {:?}", program, DEFAULT_TIMEOUT);
+        (index, Error::from_str(&message))
+    });
+    for r in running {
+        let Running {
+            index,
+            program,
+            mut child,
+            stderr,
+        } = r;
+        let status = child.wait();
+        let stderr = stderr.join().unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr);
+        match status {
+            Ok(status) => {
+                trace::event(|| format!("'{}' exited with {}", program, status));
+                if killed {
+                    // Killed below; the timeout is the failure to report
+                } else if !status.success() {
+                    stats::note_failure(FailureKind::ExitStatus);
+                    let message = format!("'{}' failed: {}", program, stderr.trim());
+                    failure = Some((index, Error::from_str(&message)));
+                } else if !stderr.is_empty() {
+                    eprintln!(
+                        "[git2-process-filter] {} warning: {}",
+                        program,
+                        stderr.trim()
+                    );
+                }
+            }
+            Err(e) => {
+                let message = format!("failed to wait for '{}': {}", program, e);
+                failure = Some((index, Error::from_str(&message)));
+            }
+        }
+    }
+    if let Some(failure) = failure {
+        return Err(failure);
+    }
+
+    let first = pipeline[0].0;
+    let last = pipeline[pipeline.len() - 1].0;
+    written
+        .map_err(|_| (first, Error::from_str("write thread panicked")))?
+        .map_err(|e| {
+            (
+                first,
+                Error::from_str(&format!("failed to write to stdin: {}", e)),
+            )
+        })?;
+    read.map_err(|e| {
+        (
+            last,
+            Error::from_str(&format!("failed to read stdout: {}", e)),
+        )
+    })?;
+
+    trace::event(|| {
+        format!(
+            "pipeline of {} commands wrote {} bytes, read {} bytes in {:?}",
+            pipeline.len(),
+            input.len(),
+            output.len(),
+            start.elapsed()
+        )
+    });
+    Ok(output)
+}
+
+/// Wait until every stage has exited. Once `DEFAULT_TIMEOUT` has passed since
+/// `start`, kill them all and return the first stage still running.
+fn wait_or_kill(running: &mut [Running], start: Instant) -> Option<(usize, String)> {
+    loop {
+        let hung = running
+            .iter_mut()
+            .position(|r| matches!(r.child.try_wait(), Ok(None)))?;
+        if start.elapsed() > DEFAULT_TIMEOUT {
+            for r in running.iter_mut() {
+                let _ = r.child.kill();
+            }
+            return Some((running[hung].index, running[hung].program.clone()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upper_chain() -> Vec<ProcessFilter> {
+        FilterChain::new("chain")
+            .command_stage("upper", "tr a-z A-Z", "tr A-Z a-z")
+            .command_stage("rot13", "tr A-Za-z N-ZA-Mn-za-m", "tr A-Za-z N-ZA-Mn-za-m")
+            .in_process_stage("reverse", |_mode: FilterMode, _path: &str, input: &[u8]| {
+                Ok(input.iter().rev().copied().collect())
+            })
+            .stages
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let stages = upper_chain();
+        let clean = run("chain", &stages, FilterMode::ToOdb, "a.txt", None, b"abc").unwrap();
+        assert_eq!(clean, b"PON");
+        let smudge = run(
+            "chain",
+            &stages,
+            FilterMode::ToWorktree,
+            "a.txt",
+            None,
+            &clean,
+        )
+        .unwrap();
+        assert_eq!(smudge, b"abc");
+    }
+
+    #[test]
+    fn test_chain_streams_large_input() {
+        let stages = FilterChain::new("chain")
+            .command_stage("a", "cat", "cat")
+            .command_stage("b", "cat", "cat")
+            .command_stage("c", "cat", "cat")
+            .stages;
+        let input: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let output = run("chain", &stages, FilterMode::ToOdb, "", None, &input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_chain_reports_failing_stage() {
+        let stages = FilterChain::new("chain")
+            .command_stage("first", "cat", "cat")
+            .command_stage("broken", "sh -c 'echo oops >&2; exit 3'", "cat")
+            .command_stage("last", "cat", "cat")
+            .stages;
+        let err = run("chain", &stages, FilterMode::ToOdb, "", None, b"x").unwrap_err();
+        assert!(
+            err.message()
+                .starts_with("filter 'chain' stage 2/3 'broken' failed to clean:"),
+            "{}",
+            err.message()
+        );
+        assert!(err.message().contains("oops"), "{}", err.message());
+
+        let stages = FilterChain::new("chain")
+            .command_stage("first", "cat", "cat")
+            .in_process_stage("rust", |_: FilterMode, _: &str, _: &[u8]| {
+                Err(Error::from_str("bad input"))
+            })
+            .stages;
+        let err = run("chain", &stages, FilterMode::ToWorktree, "", None, b"x").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "filter 'chain' stage 2/2 'rust' failed to smudge: bad input"
+        );
+    }
+}
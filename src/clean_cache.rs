@@ -0,0 +1,241 @@
+//! On-disk cache of clean output, for `filter.<name>.cleancache`.
+
+use crate::smudge_cache::write_atomic;
+use crate::trace;
+use sha2::{Digest, Sha256};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Environment variable that makes every lookup miss, so the clean filter
+/// runs again (and refreshes the cache), whatever
+/// [`CleanCache::bypass_flag`] says.
+pub(crate) const BYPASS_ENV: &str = "GIT2_PROCESS_FILTER_NO_CLEAN_CACHE";
+
+/// Files modified this recently are not cached: they may still be changing
+/// within the resolution of their modification time ("racily clean" in git).
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// size, mtime seconds, mtime nanoseconds, inode, input hash, output hash.
+const HEADER_LEN: usize = 8 + 8 + 4 + 8 + 32 + 32;
+
+/// Clean output keyed by path, stored as `<dir>/aa/<rest of the key>` where
+/// the key is the SHA-256 of the filter name, its clean command and the path.
+///
+/// An entry is only used while the file has the same size, modification
+/// time and inode as when it was cleaned, and the input has the same hash:
+/// the input may be a buffer rather than the file, and hashing it is still
+/// far cheaper than running the filter. Each entry holds the hash of the
+/// output, so corrupt entries are dropped. Cleaning a path again replaces
+/// its entry.
+pub(crate) struct CleanCache {
+    dir: PathBuf,
+    /// Filter name and command, hashed into every key.
+    scope: Vec<u8>,
+    /// Set to make every lookup miss, like [`BYPASS_ENV`].
+    bypass: Arc<AtomicBool>,
+}
+
+/// What identifies a version of a file and the content cleaned for it.
+#[derive(PartialEq, Eq)]
+struct Stat {
+    size: u64,
+    mtime: Duration,
+    inode: u64,
+    content: [u8; 32],
+}
+
+impl Stat {
+    /// The stat data of `file`, if `input` is its content as far as its
+    /// size tells.
+    fn of(file: &Path, input: &[u8]) -> Option<(Stat, Metadata)> {
+        let metadata = fs::symlink_metadata(file).ok()?;
+        if !metadata.is_file() || metadata.len() != input.len() as u64 {
+            return None;
+        }
+        let mtime = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        let content = Sha256::digest(input).into();
+        let stat = Stat {
+            size: metadata.len(),
+            mtime,
+            inode,
+            content,
+        };
+        Some((stat, metadata))
+    }
+
+    fn encode(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.size.to_be_bytes());
+        data.extend_from_slice(&self.mtime.as_secs().to_be_bytes());
+        data.extend_from_slice(&self.mtime.subsec_nanos().to_be_bytes());
+        data.extend_from_slice(&self.inode.to_be_bytes());
+        data.extend_from_slice(&self.content);
+    }
+
+    fn decode(data: &[u8]) -> Stat {
+        let u64_at = |at: usize| u64::from_be_bytes(data[at..at + 8].try_into().unwrap());
+        let nanos = u32::from_be_bytes(data[16..20].try_into().unwrap());
+        Stat {
+            size: u64_at(0),
+            mtime: Duration::new(u64_at(8), nanos),
+            inode: u64_at(20),
+            content: data[28..60].try_into().unwrap(),
+        }
+    }
+}
+
+impl CleanCache {
+    pub(crate) fn open(dir: &Path, name: &str, command: &str) -> Self {
+        let mut scope = Vec::new();
+        for part in [name, command] {
+            scope.extend_from_slice(part.as_bytes());
+            scope.push(0);
+        }
+        CleanCache {
+            dir: dir.to_path_buf(),
+            scope,
+            bypass: Arc::default(),
+        }
+    }
+
+    fn entry(&self, path: &str) -> PathBuf {
+        let key = format!(
+            "{:x}",
+            Sha256::new()
+                .chain_update(&self.scope)
+                .chain_update(path)
+                .finalize()
+        );
+        self.dir.join(&key[..2]).join(&key[2..])
+    }
+
+    /// The flag that makes every lookup miss, shared with the registration.
+    pub(crate) fn bypass_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.bypass)
+    }
+
+    fn bypassed(&self) -> bool {
+        self.bypass.load(Ordering::Relaxed)
+            || std::env::var(BYPASS_ENV)
+                .map(|value| !matches!(value.as_str(), "" | "0" | "false"))
+                .unwrap_or(false)
+    }
+
+    /// The cached clean output of `path` in `workdir`, if `input` is its
+    /// content and the file has not changed since it was cached.
+    pub(crate) fn get(&self, workdir: &Path, path: &str, input: &[u8]) -> Option<Vec<u8>> {
+        if self.bypassed() {
+            return None;
+        }
+        let entry = self.entry(path);
+        let data = fs::read(&entry).ok()?;
+        let (stat, _) = Stat::of(&workdir.join(path), input)?;
+
+        let intact = data.len() >= HEADER_LEN
+            && Sha256::digest(&data[HEADER_LEN..]).as_slice() == &data[HEADER_LEN - 32..HEADER_LEN];
+        if !intact {
+            eprintln!(
+                "[git2-process-filter] warning: dropping corrupt clean cache entry {} for '{}'",
+                entry.display(),
+                path
+            );
+            let _ = fs::remove_file(&entry);
+            return None;
+        }
+        if Stat::decode(&data) != stat {
+            return None;
+        }
+        trace::event(|| format!("clean cache hit for '{}' ({})", path, entry.display()));
+        Some(data[HEADER_LEN..].to_vec())
+    }
+
+    /// Store `output` as the clean output of `path`, whose content is
+    /// `input`. Failures only warn: the cache is an optimization.
+    pub(crate) fn put(&self, workdir: &Path, path: &str, input: &[u8], output: &[u8]) {
+        let Some((stat, metadata)) = Stat::of(&workdir.join(path), input) else {
+            return;
+        };
+        let racy = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| SystemTime::now().duration_since(mtime).ok())
+            .is_none_or(|age| age < RACY_WINDOW);
+        if racy {
+            trace::event(|| format!("clean cache skips '{}': modified too recently", path));
+            return;
+        }
+
+        let mut data = Vec::with_capacity(HEADER_LEN + output.len());
+        stat.encode(&mut data);
+        data.extend_from_slice(&Sha256::digest(output));
+        data.extend_from_slice(output);
+        if let Err(e) = write_atomic(&self.entry(path), &data) {
+            eprintln!(
+                "[git2-process-filter] warning: failed to cache clean output for '{}': {}",
+                path, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stat_invalidation() {
+        let td = TempDir::new().unwrap();
+        let cache = CleanCache::open(&td.path().join("cache"), "lfs", "git-lfs clean");
+        let file = td.path().join("a.bin");
+        let age = |secs: u64| {
+            let file = File::options().write(true).open(&file).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        fs::write(&file, b"content").unwrap();
+        // Racily clean: just written
+        cache.put(td.path(), "a.bin", b"content", b"pointer");
+        assert_eq!(cache.get(td.path(), "a.bin", b"content"), None);
+
+        age(60);
+        cache.put(td.path(), "a.bin", b"content", b"pointer");
+        assert_eq!(
+            cache.get(td.path(), "a.bin", b"content").unwrap(),
+            b"pointer"
+        );
+        // Content that is not the file's
+        assert_eq!(cache.get(td.path(), "a.bin", b"CONTENT"), None);
+        assert_eq!(cache.get(td.path(), "b.bin", b"content"), None);
+        cache.bypass_flag().store(true, Ordering::Relaxed);
+        assert_eq!(cache.get(td.path(), "a.bin", b"content"), None);
+        cache.bypass_flag().store(false, Ordering::Relaxed);
+
+        // Same size and first 64 KiB as the file, different content after
+        let big = vec![b'x'; 100 * 1024];
+        fs::write(&file, &big).unwrap();
+        age(60);
+        cache.put(td.path(), "a.bin", &big, b"pointer");
+        assert_eq!(cache.get(td.path(), "a.bin", &big).unwrap(), b"pointer");
+        let mut buffer = big.clone();
+        buffer[80 * 1024] = b'y';
+        assert_eq!(cache.get(td.path(), "a.bin", &buffer), None);
+
+        // Same size, new mtime
+        fs::write(&file, b"changed").unwrap();
+        age(30);
+        assert_eq!(cache.get(td.path(), "a.bin", b"changed"), None);
+    }
+}
@@ -0,0 +1,341 @@
+//! Built-in `working-tree-encoding` support, which libgit2 lacks.
+//!
+//! Files with `working-tree-encoding=<encoding>` are stored as UTF-8 in the
+//! repository and in `<encoding>` in the working tree, with the same checks
+//! git makes:
+//!
+//! - `UTF-16`, `UTF-32` and git's own `UTF-16LE-BOM` need a byte order
+//!   mark; `UTF-16BE`/`LE` and `UTF-32BE`/`LE` must not have one;
+//! - for the encodings in `core.checkRoundtripEncoding` (default
+//!   `SHIFT-JIS`), clean fails unless the UTF-8 content encodes back to the
+//!   exact original bytes.
+//!
+//! UTF-16 and UTF-32 are converted here; other encodings (`SHIFT-JIS`,
+//! `WINDOWS-1252`, `ISO-8859-1`, ...) use the WHATWG encoding tables.
+
+use crate::InProcessFilter;
+use encoding_rs::{DecoderResult, EncoderResult, Encoding};
+use git2::{AttrCheckFlags, AttrValue, Error, FilterMode, Repository};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The attribute that selects the working tree encoding.
+pub(crate) const ATTRIBUTE: &str = "working-tree-encoding";
+
+/// Encodings checked for a round trip when `core.checkRoundtripEncoding`
+/// is not set, as in git.
+const DEFAULT_ROUNDTRIP: &str = "SHIFT-JIS";
+
+const NATIVE_BIG_ENDIAN: bool = cfg!(target_endian = "big");
+
+/// A Unicode encoding: code unit size, byte order and byte order mark.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Utf {
+    width: usize,
+    big_endian: bool,
+    /// `Some(true)` if a BOM is required, `Some(false)` if it is prohibited,
+    /// `None` if it is optional and selects the byte order.
+    bom: Option<bool>,
+}
+
+impl Utf {
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.replace('_', "-");
+        let (width, big_endian, bom) = match name.as_str() {
+            // Written in native byte order with a BOM, like glibc's iconv
+            "UTF-16" | "UCS-2" => (2, NATIVE_BIG_ENDIAN, None),
+            "UTF-16BE" | "UCS-2BE" => (2, true, Some(false)),
+            "UTF-16LE" | "UCS-2LE" => (2, false, Some(false)),
+            "UTF-16LE-BOM" => (2, false, Some(true)),
+            "UTF-32" | "UCS-4" => (4, NATIVE_BIG_ENDIAN, None),
+            "UTF-32BE" | "UCS-4BE" => (4, true, Some(false)),
+            "UTF-32LE" | "UCS-4LE" => (4, false, Some(false)),
+            _ => return None,
+        };
+        Some(Utf {
+            width,
+            big_endian,
+            bom,
+        })
+    }
+
+    fn bom(&self, big_endian: bool) -> Vec<u8> {
+        Self::units(0xfeff, self.width, big_endian)
+    }
+
+    fn units(value: u32, width: usize, big_endian: bool) -> Vec<u8> {
+        match (width, big_endian) {
+            (2, true) => (value as u16).to_be_bytes().to_vec(),
+            (2, false) => (value as u16).to_le_bytes().to_vec(),
+            (_, true) => value.to_be_bytes().to_vec(),
+            (_, false) => value.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Decode `input`, which has already been checked for a BOM.
+    fn decode(&self, input: &[u8]) -> Option<String> {
+        let (big_endian, input) = match self.bom {
+            Some(false) => (self.big_endian, input),
+            _ if input.starts_with(&self.bom(true)) => (true, &input[self.width..]),
+            _ if input.starts_with(&self.bom(false)) => (false, &input[self.width..]),
+            _ => (self.big_endian, input),
+        };
+        if input.len() % self.width != 0 {
+            return None;
+        }
+        let units = input.chunks(self.width).map(|unit| match unit.len() {
+            2 if big_endian => u16::from_be_bytes([unit[0], unit[1]]) as u32,
+            2 => u16::from_le_bytes([unit[0], unit[1]]) as u32,
+            _ if big_endian => u32::from_be_bytes([unit[0], unit[1], unit[2], unit[3]]),
+            _ => u32::from_le_bytes([unit[0], unit[1], unit[2], unit[3]]),
+        });
+        if self.width == 2 {
+            char::decode_utf16(units.map(|unit| unit as u16))
+                .collect::<Result<_, _>>()
+                .ok()
+        } else {
+            units.map(char::from_u32).collect()
+        }
+    }
+
+    fn encode(&self, text: &str) -> Vec<u8> {
+        let mut output = match self.bom {
+            Some(false) => Vec::new(),
+            _ => self.bom(self.big_endian),
+        };
+        if self.width == 2 {
+            for unit in text.encode_utf16() {
+                output.extend(Self::units(unit as u32, 2, self.big_endian));
+            }
+        } else {
+            for c in text.chars() {
+                output.extend(Self::units(c as u32, 4, self.big_endian));
+            }
+        }
+        output
+    }
+}
+
+/// An encoding named by `working-tree-encoding`.
+enum Charset {
+    Utf8,
+    Utf(Utf),
+    Other(&'static Encoding),
+}
+
+impl Charset {
+    fn from_name(name: &str) -> Option<Self> {
+        if name == "UTF-8" || name == "UTF8" {
+            return Some(Charset::Utf8);
+        }
+        if let Some(utf) = Utf::from_name(name) {
+            return Some(Charset::Utf(utf));
+        }
+        Encoding::for_label(name.as_bytes()).map(Charset::Other)
+    }
+
+    fn decode(&self, input: &[u8]) -> Option<String> {
+        match self {
+            Charset::Utf8 => String::from_utf8(input.to_vec()).ok(),
+            Charset::Utf(utf) => utf.decode(input),
+            Charset::Other(encoding) => {
+                let mut decoder = encoding.new_decoder_without_bom_handling();
+                let mut output = String::with_capacity(
+                    decoder.max_utf8_buffer_length_without_replacement(input.len())?,
+                );
+                match decoder.decode_to_string_without_replacement(input, &mut output, true) {
+                    (DecoderResult::InputEmpty, _) => Some(output),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            Charset::Utf8 => Some(text.as_bytes().to_vec()),
+            Charset::Utf(utf) => Some(utf.encode(text)),
+            Charset::Other(encoding) => {
+                let mut encoder = encoding.new_encoder();
+                let mut output = Vec::with_capacity(
+                    encoder.max_buffer_length_from_utf8_without_replacement(text.len())?,
+                );
+                match encoder.encode_from_utf8_to_vec_without_replacement(text, &mut output, true) {
+                    (EncoderResult::InputEmpty, _) => Some(output),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Converts files between their `working-tree-encoding` and UTF-8.
+pub(crate) struct WorkingTreeEncoding {
+    /// Looks up the attribute of each path: libgit2 runs the filter for any
+    /// path that has it, but does not pass its value on.
+    repo: Mutex<Repository>,
+    /// Upper-case names from `core.checkRoundtripEncoding`.
+    roundtrip: Vec<String>,
+}
+
+impl WorkingTreeEncoding {
+    pub(crate) fn new(repo: &Repository) -> Result<Self, Error> {
+        let roundtrip = repo
+            .config()?
+            .get_string("core.checkRoundtripEncoding")
+            .unwrap_or_else(|_| DEFAULT_ROUNDTRIP.to_string());
+        let roundtrip = roundtrip
+            .split([',', ' '])
+            .filter(|name| !name.is_empty())
+            .map(str::to_uppercase)
+            .collect();
+        Ok(WorkingTreeEncoding {
+            repo: Mutex::new(Repository::open(repo.path())?),
+            roundtrip,
+        })
+    }
+
+    /// The upper-cased encoding of `path`, or `None` if it has none.
+    fn encoding(&self, path: &str) -> Result<Option<String>, Error> {
+        let repo = self.repo.lock().unwrap_or_else(|e| e.into_inner());
+        let value = repo.get_attr(Path::new(path), ATTRIBUTE, AttrCheckFlags::FILE_THEN_INDEX)?;
+        match AttrValue::from_string(value) {
+            AttrValue::String(name) if !name.is_empty() => Ok(Some(name.to_uppercase())),
+            AttrValue::True | AttrValue::False => Err(Error::from_str(&format!(
+                "true/false are no valid working-tree-encodings ('{}')",
+                path
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    fn clean(
+        &self,
+        path: &str,
+        name: &str,
+        charset: &Charset,
+        input: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if let Charset::Utf(utf) = charset {
+            let has_bom = input.starts_with(&utf.bom(true)) || input.starts_with(&utf.bom(false));
+            if utf.bom == Some(false) && has_bom {
+                return Err(Error::from_str(&format!(
+                    "BOM is prohibited in '{}' if encoded as {}",
+                    path, name
+                )));
+            }
+            if utf.bom == Some(true) && !input.starts_with(&utf.bom(utf.big_endian))
+                || utf.bom.is_none() && !has_bom
+            {
+                return Err(Error::from_str(&format!(
+                    "BOM is required in '{}' if encoded as {}",
+                    path, name
+                )));
+            }
+        }
+
+        let text = charset.decode(input).ok_or_else(|| {
+            Error::from_str(&format!(
+                "failed to encode '{}' from {} to UTF-8",
+                path, name
+            ))
+        })?;
+        if self.roundtrip.iter().any(|n| n == name)
+            && charset.encode(&text).as_deref() != Some(input)
+        {
+            return Err(Error::from_str(&format!(
+                "encoding '{}' from {} to UTF-8 and back is not the same",
+                path, name
+            )));
+        }
+        Ok(text.into_bytes())
+    }
+}
+
+impl InProcessFilter for WorkingTreeEncoding {
+    fn apply(&self, mode: FilterMode, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let Some(name) = self.encoding(path)? else {
+            return Ok(input.to_vec());
+        };
+        let charset = Charset::from_name(&name);
+        if matches!(charset, Some(Charset::Utf8)) || input.is_empty() {
+            return Ok(input.to_vec());
+        }
+
+        // Like git, only clean refuses: a smudge that cannot be encoded
+        // leaves the UTF-8 content in the working tree
+        let encoded = match (mode, &charset) {
+            (FilterMode::ToOdb, Some(charset)) => return self.clean(path, &name, charset, input),
+            (FilterMode::ToOdb, None) => {
+                return Err(Error::from_str(&format!(
+                    "unsupported working-tree-encoding '{}' for '{}'",
+                    name, path
+                )))
+            }
+            (FilterMode::ToWorktree, Some(charset)) => std::str::from_utf8(input)
+                .ok()
+                .and_then(|text| charset.encode(text)),
+            (FilterMode::ToWorktree, None) => None,
+        };
+        Ok(encoded.unwrap_or_else(|| {
+            eprintln!(
+                "[git2-process-filter] warning: failed to encode '{}' from UTF-8 to {}",
+                path, name
+            );
+            input.to_vec()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf_conversions() {
+        let text = "h\u{e9}llo \u{1f600}";
+        let utf16 = Charset::from_name("UTF-16").unwrap();
+        assert_eq!(utf16.decode(&utf16.encode(text).unwrap()).unwrap(), text);
+
+        // Either BOM is honoured when decoding plain UTF-16
+        let le = Charset::from_name("UTF-16LE-BOM")
+            .unwrap()
+            .encode(text)
+            .unwrap();
+        assert_eq!(&le[..4], &[0xff, 0xfe, b'h', 0x00]);
+        assert_eq!(utf16.decode(&le).unwrap(), text);
+        let mut be = vec![0xfe, 0xff];
+        be.extend(
+            Charset::from_name("UTF-16BE")
+                .unwrap()
+                .encode(text)
+                .unwrap(),
+        );
+        assert_eq!(utf16.decode(&be).unwrap(), text);
+
+        let utf32le = Charset::from_name("UTF-32LE").unwrap();
+        let encoded = utf32le.encode(text).unwrap();
+        assert_eq!(encoded.len(), 4 * text.chars().count());
+        assert_eq!(utf32le.decode(&encoded).unwrap(), text);
+
+        // Unpaired surrogate, odd length
+        let utf16le = Charset::from_name("UTF-16LE").unwrap();
+        assert!(utf16le.decode(&[0x00, 0xd8]).is_none());
+        assert!(utf16le.decode(&[b'h', 0x00, b'i']).is_none());
+    }
+
+    #[test]
+    fn test_legacy_encodings() {
+        let sjis = Charset::from_name("SHIFT-JIS").unwrap();
+        let text = "\u{65e5}\u{672c}";
+        let encoded = sjis.encode(text).unwrap();
+        assert_eq!(encoded, [0x93, 0xfa, 0x96, 0x7b]);
+        assert_eq!(sjis.decode(&encoded).unwrap(), text);
+        // Not representable in Shift_JIS
+        assert!(sjis.encode("\u{1f600}").is_none());
+
+        let latin1 = Charset::from_name("WINDOWS-1252").unwrap();
+        assert_eq!(latin1.encode("caf\u{e9}").unwrap(), b"caf\xe9");
+        assert!(Charset::from_name("NO-SUCH-ENCODING").is_none());
+    }
+}
@@ -0,0 +1,362 @@
+//! Built-in git-crypt filter that does not need the `git-crypt` binary.
+//!
+//! Selected with `filter.<name>.builtin = git-crypt`, or registered directly
+//! as an [`InProcessFilter`]. It reads the symmetric key that `git-crypt
+//! unlock` leaves in `.git/git-crypt/keys/default` and produces exactly what
+//! `git-crypt clean` and `git-crypt smudge` do:
+//!
+//! - clean writes `\0GITCRYPT\0`, a 12-byte nonce (the first 12 bytes of the
+//!   HMAC-SHA1 of the content) and the content encrypted with AES-256 in CTR
+//!   mode, with the nonce followed by a 32-bit big-endian block counter;
+//! - smudge decrypts, and fails if the HMAC of the result does not match the
+//!   nonce. Content without the header is checked out as is, with a warning.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use git2::Repository;
+//! use git2_process_filter::git_crypt::GitCryptFilter;
+//! use git2_process_filter::register_in_process_filter;
+//!
+//! let repo = Repository::open(".")?;
+//! let _reg = register_in_process_filter("git-crypt", true, GitCryptFilter::open(&repo)?)?;
+//! # Ok::<(), git2::Error>(())
+//! ```
+
+use crate::InProcessFilter;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use git2::{Error, FilterMode, Repository};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::path::Path;
+
+/// Header of every file encrypted by git-crypt.
+pub const HEADER: &[u8; 10] = b"\0GITCRYPT\0";
+
+const NONCE_LEN: usize = 12;
+const AES_KEY_LEN: usize = 32;
+const HMAC_KEY_LEN: usize = 64;
+
+/// The CTR counter is 32 bits, so at most 2^32 blocks can be encrypted.
+const MAX_CRYPT_BYTES: u64 = (1 << 32) * 16;
+
+/// Header of a key file in the current format.
+const KEY_FILE_MAGIC: &[u8; 12] = b"\0GITCRYPTKEY";
+const KEY_FILE_FORMAT: u32 = 2;
+const KEY_FIELD_END: u32 = 0;
+/// The only header field: the key name, which is not needed.
+const KEY_HEADER_FIELD_NAME: u32 = 1;
+const KEY_FIELD_VERSION: u32 = 1;
+const KEY_FIELD_AES_KEY: u32 = 3;
+const KEY_FIELD_HMAC_KEY: u32 = 5;
+/// Longest field git-crypt accepts in a key file.
+const MAX_FIELD_LEN: usize = 1 << 20;
+
+type Aes256Ctr = ctr::Ctr32BE<aes::Aes256>;
+
+/// One version of a git-crypt key.
+struct Key {
+    version: u32,
+    aes: [u8; AES_KEY_LEN],
+    hmac: [u8; HMAC_KEY_LEN],
+}
+
+impl Key {
+    /// Parse a key file, returning its latest key: the one git-crypt uses
+    /// for both clean and smudge.
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let Some(mut rest) = data.strip_prefix(KEY_FILE_MAGIC) else {
+            // Keys from git-crypt before 0.4 are the raw AES and HMAC keys
+            if data.len() == AES_KEY_LEN + HMAC_KEY_LEN {
+                let (aes, hmac) = data.split_at(AES_KEY_LEN);
+                return Ok(Key {
+                    version: 0,
+                    aes: aes.try_into().unwrap(),
+                    hmac: hmac.try_into().unwrap(),
+                });
+            }
+            return Err("not a git-crypt key file".to_string());
+        };
+
+        let format = read_u32(&mut rest)?;
+        if format != KEY_FILE_FORMAT {
+            return Err(format!("unsupported key file format version {}", format));
+        }
+        // Header fields (the key name) are not needed, but like in entries,
+        // odd fields are critical
+        loop {
+            match read_field(&mut rest)?.0 {
+                KEY_FIELD_END => break,
+                KEY_HEADER_FIELD_NAME => {}
+                id if id & 1 == 1 => {
+                    return Err(format!("unknown critical key file header field {}", id))
+                }
+                _ => {}
+            }
+        }
+
+        let mut latest: Option<Key> = None;
+        while !rest.is_empty() {
+            let key = Self::parse_entry(&mut rest)?;
+            if latest.as_ref().is_none_or(|l| key.version > l.version) {
+                latest = Some(key);
+            }
+        }
+        latest.ok_or_else(|| "key file contains no keys".to_string())
+    }
+
+    /// Parse one key entry: fields up to `KEY_FIELD_END`.
+    fn parse_entry(rest: &mut &[u8]) -> Result<Self, String> {
+        let (mut version, mut aes, mut hmac) = (None, None, None);
+        loop {
+            let (id, value) = read_field(rest)?;
+            match id {
+                KEY_FIELD_END => break,
+                KEY_FIELD_VERSION => {
+                    let mut value = value;
+                    version = Some(read_u32(&mut value)?);
+                }
+                KEY_FIELD_AES_KEY => aes = Some(fixed::<AES_KEY_LEN>(value, "AES key")?),
+                KEY_FIELD_HMAC_KEY => hmac = Some(fixed::<HMAC_KEY_LEN>(value, "HMAC key")?),
+                // Odd fields are critical: an unknown one means a newer format
+                id if id & 1 == 1 => return Err(format!("unknown critical key field {}", id)),
+                _ => {}
+            }
+        }
+        match (aes, hmac) {
+            (Some(aes), Some(hmac)) => Ok(Key {
+                version: version.unwrap_or(0),
+                aes,
+                hmac,
+            }),
+            _ => Err("key entry without an AES or HMAC key".to_string()),
+        }
+    }
+
+    /// The nonce of `content`: the start of its HMAC-SHA1.
+    fn nonce(&self, content: &[u8]) -> [u8; NONCE_LEN] {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.hmac).expect("HMAC takes any key size");
+        mac.update(content);
+        mac.finalize().into_bytes()[..NONCE_LEN].try_into().unwrap()
+    }
+
+    /// Encrypt or decrypt `data` in place.
+    fn apply_keystream(&self, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+        let mut iv = [0u8; 16];
+        iv[..NONCE_LEN].copy_from_slice(nonce);
+        Aes256Ctr::new(&self.aes.into(), &iv.into()).apply_keystream(data);
+    }
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, String> {
+    let (value, rest) = data
+        .split_first_chunk::<4>()
+        .ok_or_else(|| "key file is truncated".to_string())?;
+    *data = rest;
+    Ok(u32::from_be_bytes(*value))
+}
+
+/// Read a `(id, value)` field. `KEY_FIELD_END` has no length or value.
+fn read_field<'a>(data: &mut &'a [u8]) -> Result<(u32, &'a [u8]), String> {
+    let id = read_u32(data)?;
+    if id == KEY_FIELD_END {
+        return Ok((id, &[]));
+    }
+    let len = read_u32(data)? as usize;
+    if len > MAX_FIELD_LEN || len > data.len() {
+        return Err("key file is truncated".to_string());
+    }
+    let (value, rest) = data.split_at(len);
+    *data = rest;
+    Ok((id, value))
+}
+
+fn fixed<const N: usize>(value: &[u8], what: &str) -> Result<[u8; N], String> {
+    value
+        .try_into()
+        .map_err(|_| format!("{} is {} bytes instead of {}", what, value.len(), N))
+}
+
+/// The built-in git-crypt filter.
+pub struct GitCryptFilter {
+    key: Key,
+}
+
+impl GitCryptFilter {
+    /// Use the repository's default key, `.git/git-crypt/keys/default`,
+    /// which exists once the repository is unlocked.
+    pub fn open(repo: &Repository) -> Result<Self, Error> {
+        Self::from_key_file(&repo.commondir().join("git-crypt/keys/default"))
+    }
+
+    /// Use the key in `path`, e.g. one written by `git-crypt export-key`.
+    pub fn from_key_file(path: &Path) -> Result<Self, Error> {
+        let data = std::fs::read(path).map_err(|e| {
+            Error::from_str(&format!(
+                "failed to read git-crypt key {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let key = Key::parse(&data).map_err(|e| {
+            Error::from_str(&format!("invalid git-crypt key {}: {}", path.display(), e))
+        })?;
+        Ok(GitCryptFilter { key })
+    }
+
+    /// Encrypt `input` exactly like `git-crypt clean`.
+    fn clean(&self, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        if input.len() as u64 > MAX_CRYPT_BYTES {
+            return Err(Error::from_str(&format!(
+                "'{}' is too large for git-crypt to encrypt",
+                path
+            )));
+        }
+        let nonce = self.key.nonce(input);
+        let mut output = Vec::with_capacity(HEADER.len() + NONCE_LEN + input.len());
+        output.extend_from_slice(HEADER);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(input);
+        self.key
+            .apply_keystream(&nonce, &mut output[HEADER.len() + NONCE_LEN..]);
+        Ok(output)
+    }
+
+    /// Decrypt `input` exactly like `git-crypt smudge`.
+    fn smudge(&self, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let encrypted = input
+            .strip_prefix(HEADER)
+            .and_then(|rest| rest.split_first_chunk::<NONCE_LEN>());
+        let Some((nonce, ciphertext)) = encrypted else {
+            eprintln!(
+                "[git2-process-filter] warning: '{}' is not encrypted by git-crypt; checking it out as is",
+                path
+            );
+            return Ok(input.to_vec());
+        };
+
+        let mut output = ciphertext.to_vec();
+        self.key.apply_keystream(nonce, &mut output);
+        if self.key.nonce(&output) != *nonce {
+            return Err(Error::from_str(&format!(
+                "git-crypt HMAC check failed for '{}': the file was tampered with or encrypted with another key",
+                path
+            )));
+        }
+        Ok(output)
+    }
+}
+
+impl InProcessFilter for GitCryptFilter {
+    fn apply(&self, mode: FilterMode, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        match mode {
+            FilterMode::ToOdb => self.clean(path, input),
+            FilterMode::ToWorktree => self.smudge(path, input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A key file in git-crypt's current format, with one entry per version.
+    fn key_file(versions: &[u32], extra_field: Option<u32>) -> Vec<u8> {
+        let field = |data: &mut Vec<u8>, id: u32, value: &[u8]| {
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            data.extend_from_slice(value);
+        };
+        let mut data = KEY_FILE_MAGIC.to_vec();
+        data.extend_from_slice(&KEY_FILE_FORMAT.to_be_bytes());
+        field(&mut data, 1, b"default");
+        data.extend_from_slice(&KEY_FIELD_END.to_be_bytes());
+        for &version in versions {
+            field(&mut data, KEY_FIELD_VERSION, &version.to_be_bytes());
+            field(&mut data, KEY_FIELD_AES_KEY, &[version as u8; AES_KEY_LEN]);
+            field(
+                &mut data,
+                KEY_FIELD_HMAC_KEY,
+                &[!version as u8; HMAC_KEY_LEN],
+            );
+            if let Some(id) = extra_field {
+                field(&mut data, id, b"future");
+            }
+            data.extend_from_slice(&KEY_FIELD_END.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_key_file() {
+        let key = Key::parse(&key_file(&[0, 2, 1], None)).unwrap();
+        assert_eq!(key.version, 2);
+        assert_eq!(key.aes, [2; AES_KEY_LEN]);
+        assert_eq!(key.hmac, [!2; HMAC_KEY_LEN]);
+
+        // Unknown even fields are skipped, unknown odd fields are critical
+        assert!(Key::parse(&key_file(&[0], Some(100))).is_ok());
+        let err = Key::parse(&key_file(&[0], Some(101))).err().unwrap();
+        assert_eq!(err, "unknown critical key field 101");
+
+        let legacy = Key::parse(&[7; AES_KEY_LEN + HMAC_KEY_LEN]).unwrap();
+        assert_eq!((legacy.aes, legacy.hmac), ([7; 32], [7; 64]));
+
+        let data = key_file(&[0], None);
+        assert!(Key::parse(&data[..data.len() - 10]).is_err());
+        assert!(Key::parse(&key_file(&[], None)).is_err());
+        assert!(Key::parse(b"secret").is_err());
+    }
+
+    #[test]
+    fn test_parse_key_file_header() {
+        // Magic, format 2, then the header: key name "k", field 4, field 7
+        let header: &[u8] = b"\0GITCRYPTKEY\0\0\0\x02\
+            \0\0\0\x01\0\0\0\x01k\
+            \0\0\0\x04\0\0\0\x02ok\
+            \0\0\0\x07\0\0\0\x03new\
+            \0\0\0\0";
+        let entry = {
+            let mut entry = b"\0\0\0\x03\0\0\0\x20".to_vec();
+            entry.extend_from_slice(&[1; AES_KEY_LEN]);
+            entry.extend_from_slice(b"\0\0\0\x05\0\0\0\x40");
+            entry.extend_from_slice(&[2; HMAC_KEY_LEN]);
+            entry.extend_from_slice(b"\0\0\0\0");
+            entry
+        };
+        let err = Key::parse(&[header, &entry].concat()).err().unwrap();
+        assert_eq!(err, "unknown critical key file header field 7");
+
+        // Without the critical field, the even one is skipped
+        let (start, end) = header.split_at(34);
+        let header = [start, &end[11..]].concat();
+        let key = Key::parse(&[&header[..], &entry].concat()).unwrap();
+        assert_eq!((key.version, key.aes, key.hmac), (0, [1; 32], [2; 64]));
+    }
+
+    #[test]
+    fn test_clean_smudge_round_trip() {
+        let filter = GitCryptFilter {
+            key: Key::parse(&key_file(&[0], None)).unwrap(),
+        };
+        let content: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        let encrypted = filter.clean("a", &content).unwrap();
+        assert!(encrypted.starts_with(HEADER));
+        assert_eq!(encrypted.len(), HEADER.len() + NONCE_LEN + content.len());
+        // Deterministic, so unchanged files stay unchanged in the index
+        assert_eq!(filter.clean("a", &content).unwrap(), encrypted);
+        assert_eq!(filter.smudge("a", &encrypted).unwrap(), content);
+
+        let empty = filter.clean("e", b"").unwrap();
+        assert_eq!(empty.len(), HEADER.len() + NONCE_LEN);
+        assert_eq!(filter.smudge("e", &empty).unwrap(), b"");
+
+        assert_eq!(filter.smudge("p", b"plain").unwrap(), b"plain");
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let err = filter.smudge("a", &tampered).unwrap_err();
+        assert!(err.message().contains("HMAC check failed"), "{}", err);
+    }
+}
@@ -0,0 +1,62 @@
+//! Filters implemented in Rust and run inside this process.
+
+use git2::{Error, FilterMode};
+
+/// A filter implemented in Rust, registered with
+/// [`register_in_process_filter`](crate::register_in_process_filter).
+///
+/// It goes through the same machinery as external commands (registration
+/// handle, tracing, error reporting), so a filter name can switch between an
+/// external command and Rust code without other changes. Use it for trivial
+/// transforms where spawning a process per file is wasteful.
+///
+/// Closures taking `(FilterMode, &str, &[u8])` implement this trait.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::{Error, FilterMode};
+/// use git2_process_filter::{register_in_process_filter, InProcessFilter};
+///
+/// /// Strips trailing whitespace on clean.
+/// struct TrimTrailing;
+///
+/// impl InProcessFilter for TrimTrailing {
+///     fn apply(&self, mode: FilterMode, _path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+///         if mode == FilterMode::ToWorktree {
+///             return Ok(input.to_vec());
+///         }
+///         let text = std::str::from_utf8(input).map_err(|e| Error::from_str(&e.to_string()))?;
+///         Ok(text.lines().map(|l| format!("{}\n", l.trim_end())).collect::<String>().into_bytes())
+///     }
+/// }
+///
+/// let _trim = register_in_process_filter("trim", false, TrimTrailing)?;
+///
+/// // Or with a closure
+/// let _upper = register_in_process_filter(
+///     "upper",
+///     false,
+///     |mode: FilterMode, _path: &str, input: &[u8]| match mode {
+///         FilterMode::ToOdb => Ok(input.to_ascii_uppercase()),
+///         FilterMode::ToWorktree => Ok(input.to_ascii_lowercase()),
+///     },
+/// )?;
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub trait InProcessFilter: Send + Sync + 'static {
+    /// Transform `input` for the file at `path`: clean for
+    /// [`FilterMode::ToOdb`], smudge for [`FilterMode::ToWorktree`].
+    ///
+    /// An error fails the blob, just like a failing `clean`/`smudge` command.
+    fn apply(&self, mode: FilterMode, path: &str, input: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+impl<F> InProcessFilter for F
+where
+    F: Fn(FilterMode, &str, &[u8]) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+{
+    fn apply(&self, mode: FilterMode, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        self(mode, path, input)
+    }
+}
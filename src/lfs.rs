@@ -0,0 +1,396 @@
+//! Built-in Git LFS filter that does not need the `git-lfs` binary.
+//!
+//! Selected with `filter.<name>.builtin = lfs`, or registered directly as an
+//! [`InProcessFilter`]. Clean hashes the content with SHA-256, stores it in
+//! the local LFS object store (`.git/lfs`, or `lfs.storage`) and replaces it
+//! with the same spec v1 [`Pointer`] git-lfs writes. Smudge looks the object up in
+//! the local store (and in the stores of alternate object directories),
+//! verifies it and writes the content. What happens when the object is not
+//! available locally is decided by [`MissingObject`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use git2::Repository;
+//! use git2_process_filter::lfs::{LfsFilter, MissingObject};
+//! use git2_process_filter::register_in_process_filter;
+//!
+//! let repo = Repository::open(".")?;
+//! let filter = LfsFilter::open(&repo)?.missing_objects(MissingObject::KeepPointer);
+//! let _reg = register_in_process_filter("lfs", true, filter)?;
+//! # Ok::<(), git2::Error>(())
+//! ```
+
+mod fetch;
+#[cfg(feature = "http")]
+mod http;
+mod pointer;
+mod store;
+mod transfer;
+
+pub use fetch::LfsFetcher;
+#[cfg(feature = "http")]
+pub use http::HttpFetcher;
+pub use transfer::TransferAgent;
+
+pub use pointer::{Extension, Pointer, PointerError, MAX_POINTER_SIZE, VERSION};
+pub(crate) use store::LfsStore;
+
+use crate::InProcessFilter;
+use git2::{Error, FilterMode, Repository};
+
+/// What smudge does when an object is not in any local store.
+pub enum MissingObject {
+    /// Check out the pointer itself, like `GIT_LFS_SKIP_SMUDGE=1`.
+    KeepPointer,
+    /// Fail the checkout of that file.
+    Fail,
+    /// Retrieve the content, e.g. with `HttpFetcher` or a [`TransferAgent`].
+    /// The content is verified and added to the local store.
+    Fetch(Box<dyn LfsFetcher>),
+}
+
+impl MissingObject {
+    /// Parse `filter.<name>.missing`: `keep`, `fail`, or `fetch` (through the
+    /// standalone transfer agent if one is configured, otherwise from the LFS
+    /// server of the default remote, with the `http` feature).
+    pub(crate) fn from_config(repo: &Repository, value: &str) -> Result<MissingObject, Error> {
+        match value {
+            "keep" => Ok(MissingObject::KeepPointer),
+            "fail" => Ok(MissingObject::Fail),
+            "fetch" => match TransferAgent::standalone(repo, None)? {
+                Some(agent) => Ok(MissingObject::Fetch(Box::new(agent))),
+                #[cfg(feature = "http")]
+                None => Ok(MissingObject::Fetch(Box::new(HttpFetcher::from_repo(
+                    repo, None,
+                )?))),
+                #[cfg(not(feature = "http"))]
+                None => Err(Error::from_str(
+                    "fetch needs lfs.standalonetransferagent without the http feature",
+                )),
+            },
+            _ => Err(Error::from_str(&format!("unknown policy '{}'", value))),
+        }
+    }
+}
+
+/// The built-in LFS filter.
+pub struct LfsFilter {
+    store: LfsStore,
+    missing: MissingObject,
+}
+
+impl LfsFilter {
+    /// Open the LFS object store of `repo`. Missing objects fail smudge
+    /// unless [`missing_objects`](Self::missing_objects) says otherwise.
+    pub fn open(repo: &Repository) -> Result<Self, Error> {
+        Ok(LfsFilter {
+            store: LfsStore::open(repo)?,
+            missing: MissingObject::Fail,
+        })
+    }
+
+    /// Set what smudge does with objects that are not in any local store.
+    pub fn missing_objects(mut self, policy: MissingObject) -> Self {
+        self.missing = policy;
+        self
+    }
+
+    /// Store `input` and return its pointer, exactly like `git-lfs clean`.
+    fn clean(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        // git-lfs never turns empty files into pointers, and passes content
+        // that already is a pointer through unchanged
+        if input.is_empty() || Pointer::parse(input).is_ok() {
+            return Ok(input.to_vec());
+        }
+
+        let pointer = Pointer::from_content(input);
+        self.store.insert(&pointer.oid, input).map_err(|e| {
+            Error::from_str(&format!(
+                "failed to store LFS object {}: {}",
+                pointer.oid, e
+            ))
+        })?;
+        Ok(pointer.to_string().into_bytes())
+    }
+
+    /// Replace a pointer with the object it references.
+    pub(crate) fn smudge(&self, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        // Like git-lfs, anything that is not a pointer is checked out as is
+        let Ok(pointer) = Pointer::parse(input) else {
+            return Ok(input.to_vec());
+        };
+        // The object is the output of the extensions' clean, not the file:
+        // refuse it rather than check out the wrong content
+        if !pointer.extensions.is_empty() {
+            let names: Vec<&str> = pointer.extensions.iter().map(|e| e.name.as_str()).collect();
+            let reason = format!(
+                "LFS pointer for '{}' uses extensions ({}), which are not supported",
+                path,
+                names.join(", ")
+            );
+            if let MissingObject::KeepPointer = self.missing {
+                eprintln!(
+                    "[git2-process-filter] warning: {}; keeping the pointer",
+                    reason
+                );
+                return Ok(input.to_vec());
+            }
+            return Err(Error::from_str(&reason));
+        }
+
+        if let Some(object) = self.store.find(&pointer.oid) {
+            let content = std::fs::read(&object).map_err(|e| {
+                Error::from_str(&format!(
+                    "failed to read LFS object {}: {}",
+                    object.display(),
+                    e
+                ))
+            })?;
+            verify(&pointer, &content)
+                .map_err(|e| Error::from_str(&format!("{} ({})", e, object.display())))?;
+            return Ok(content);
+        }
+
+        match &self.missing {
+            MissingObject::KeepPointer => {
+                eprintln!(
+                    "[git2-process-filter] warning: LFS object {} for '{}' is not available locally; keeping the pointer",
+                    pointer.oid, path
+                );
+                Ok(input.to_vec())
+            }
+            MissingObject::Fail => Err(Error::from_str(&format!(
+                "LFS object {} for '{}' is not available locally",
+                pointer.oid, path
+            ))),
+            MissingObject::Fetch(fetcher) => {
+                let content = fetcher.fetch(&pointer.oid, pointer.size)?;
+                verify(&pointer, &content)?;
+                self.store.insert(&pointer.oid, &content).map_err(|e| {
+                    Error::from_str(&format!(
+                        "failed to store LFS object {}: {}",
+                        pointer.oid, e
+                    ))
+                })?;
+                Ok(content)
+            }
+        }
+    }
+}
+
+/// Check that `content` is what `pointer` references.
+fn verify(pointer: &Pointer, content: &[u8]) -> Result<(), Error> {
+    if content.len() as u64 != pointer.size {
+        return Err(Error::from_str(&format!(
+            "LFS object {} is corrupt: expected {} bytes, got {}",
+            pointer.oid,
+            pointer.size,
+            content.len()
+        )));
+    }
+    let oid = Pointer::from_content(content).oid;
+    if oid != pointer.oid {
+        return Err(Error::from_str(&format!(
+            "LFS object {} is corrupt: content hashes to {}",
+            pointer.oid, oid
+        )));
+    }
+    Ok(())
+}
+
+/// Check that clean output of filter `name` for `path` is an LFS pointer
+/// whose object is in `store`, for `filter.<name>.verifypointer`.
+///
+/// Empty output is accepted, as git-lfs never turns empty files into pointers.
+pub(crate) fn verify_clean_output(
+    store: &LfsStore,
+    name: &str,
+    path: &str,
+    output: &[u8],
+) -> Result<(), Error> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    let pointer = Pointer::parse(output).map_err(|e| {
+        Error::from_str(&format!(
+            "filter '{}' did not clean '{}' into an LFS pointer ({} bytes of output): {}",
+            name,
+            path,
+            output.len(),
+            e
+        ))
+    })?;
+    let object = store.find(&pointer.oid).ok_or_else(|| {
+        Error::from_str(&format!(
+            "filter '{}' cleaned '{}' into a pointer to LFS object {}, which is not in the local store",
+            name, path, pointer.oid
+        ))
+    })?;
+    let len = std::fs::metadata(&object).map(|m| m.len()).unwrap_or(0);
+    if len != pointer.size {
+        return Err(Error::from_str(&format!(
+            "filter '{}' cleaned '{}' into a pointer to LFS object {} of {} bytes, but {} has {}",
+            name,
+            path,
+            pointer.oid,
+            pointer.size,
+            object.display(),
+            len
+        )));
+    }
+    Ok(())
+}
+
+impl InProcessFilter for LfsFilter {
+    fn apply(&self, mode: FilterMode, path: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        match mode {
+            FilterMode::ToOdb => self.clean(input),
+            FilterMode::ToWorktree => self.smudge(path, input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const HELLO_OID: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    fn hello_pointer() -> Vec<u8> {
+        Pointer::from_content(b"hello\n").to_string().into_bytes()
+    }
+
+    #[test]
+    fn test_clean_writes_pointer_and_object() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let filter = LfsFilter::open(&repo).unwrap();
+
+        let pointer = filter.clean(b"hello\n").unwrap();
+        assert_eq!(
+            String::from_utf8(pointer.clone()).unwrap(),
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 6\n",
+                HELLO_OID
+            )
+        );
+        let object = td.path().join(".git/lfs/objects/58/91").join(HELLO_OID);
+        assert_eq!(fs::read(object).unwrap(), b"hello\n");
+
+        // Cleaning a pointer or an empty file changes nothing
+        assert_eq!(filter.clean(&pointer).unwrap(), pointer);
+        assert_eq!(filter.clean(b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn test_smudge_verifies_object() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let filter = LfsFilter::open(&repo).unwrap();
+
+        filter.clean(b"hello\n").unwrap();
+        assert_eq!(
+            filter.smudge("a.bin", &hello_pointer()).unwrap(),
+            b"hello\n"
+        );
+        assert_eq!(
+            filter.smudge("a.bin", b"not a pointer").unwrap(),
+            b"not a pointer"
+        );
+
+        let object = td.path().join(".git/lfs/objects/58/91").join(HELLO_OID);
+        fs::write(&object, b"HELLO\n").unwrap();
+        let err = filter.smudge("a.bin", &hello_pointer()).unwrap_err();
+        assert!(
+            err.message().contains("content hashes to"),
+            "{}",
+            err.message()
+        );
+        fs::write(&object, b"hello").unwrap();
+        let err = filter.smudge("a.bin", &hello_pointer()).unwrap_err();
+        assert!(
+            err.message().contains("expected 6 bytes, got 5"),
+            "{}",
+            err.message()
+        );
+    }
+
+    #[test]
+    fn test_smudge_refuses_extensions() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let filter = LfsFilter::open(&repo).unwrap();
+
+        // The stored object is what the extension's clean produced
+        filter.clean(b"hello\n").unwrap();
+        let mut pointer = Pointer::from_content(b"hello\n");
+        pointer.extensions.push(Extension {
+            name: "foo".to_string(),
+            priority: 0,
+            oid: Pointer::from_content(b"original\n").oid,
+        });
+        let pointer = pointer.to_string().into_bytes();
+        let err = filter.smudge("a.bin", &pointer).unwrap_err();
+        assert!(
+            err.message().contains("uses extensions (foo)"),
+            "{}",
+            err.message()
+        );
+
+        let filter = filter.missing_objects(MissingObject::KeepPointer);
+        assert_eq!(filter.smudge("a.bin", &pointer).unwrap(), pointer);
+    }
+
+    #[test]
+    fn test_smudge_missing_object_policy() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+
+        let filter = LfsFilter::open(&repo).unwrap();
+        let err = filter.smudge("a.bin", &hello_pointer()).unwrap_err();
+        assert!(
+            err.message().contains("not available locally"),
+            "{}",
+            err.message()
+        );
+
+        let filter = filter.missing_objects(MissingObject::KeepPointer);
+        assert_eq!(
+            filter.smudge("a.bin", &hello_pointer()).unwrap(),
+            hello_pointer()
+        );
+
+        let filter =
+            filter.missing_objects(MissingObject::Fetch(Box::new(|oid: &str, size: u64| {
+                assert_eq!((oid, size), (HELLO_OID, 6));
+                Ok(b"hello\n".to_vec())
+            })));
+        assert_eq!(
+            filter.smudge("a.bin", &hello_pointer()).unwrap(),
+            b"hello\n"
+        );
+        // The fetched object was stored, so the next smudge needs no hook
+        let filter = filter.missing_objects(MissingObject::Fail);
+        assert_eq!(
+            filter.smudge("a.bin", &hello_pointer()).unwrap(),
+            b"hello\n"
+        );
+
+        let filter = LfsFilter::open(&repo)
+            .unwrap()
+            .missing_objects(MissingObject::Fetch(Box::new(|_: &str, _: u64| {
+                Ok(b"wrong\n".to_vec())
+            })));
+        let pointer = Pointer {
+            oid: "0".repeat(64),
+            size: 6,
+            extensions: Vec::new(),
+        };
+        assert!(filter
+            .smudge("b.bin", pointer.to_string().as_bytes())
+            .is_err());
+    }
+}
@@ -0,0 +1,31 @@
+//! Retrieving LFS objects that are not in the local store.
+
+use git2::{Config, Error};
+
+/// Retrieves LFS objects that smudge cannot find locally, used with
+/// [`MissingObject::Fetch`](super::MissingObject::Fetch).
+///
+/// The content does not need to be verified: the filter checks its size and
+/// SHA-256 and adds it to the local store.
+///
+/// Closures taking `(oid, size)` implement this trait.
+pub trait LfsFetcher: Send + Sync + 'static {
+    /// Retrieve the content of object `oid`, which is `size` bytes.
+    fn fetch(&self, oid: &str, size: u64) -> Result<Vec<u8>, Error>;
+}
+
+impl<F> LfsFetcher for F
+where
+    F: Fn(&str, u64) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+{
+    fn fetch(&self, oid: &str, size: u64) -> Result<Vec<u8>, Error> {
+        self(oid, size)
+    }
+}
+
+/// The remote git-lfs uses when none is given.
+pub(super) fn default_remote(config: &Config) -> String {
+    config
+        .get_string("remote.lfsdefault")
+        .unwrap_or_else(|_| "origin".to_string())
+}
@@ -0,0 +1,290 @@
+//! Downloading LFS objects with the batch API, with the `http` feature.
+
+use super::fetch::default_remote;
+use super::LfsFetcher;
+use crate::trace;
+use git2::{Config, Error, Repository};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::time::Duration;
+
+/// Media type of LFS batch API requests and responses.
+const LFS_MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// Timeout for a single batch request or download (5 minutes).
+const HTTP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Downloads objects with the LFS batch API over HTTP(S), using the `basic`
+/// transfer adapter.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::Repository;
+/// use git2_process_filter::lfs::{HttpFetcher, LfsFilter, MissingObject};
+/// use git2_process_filter::register_in_process_filter;
+///
+/// let repo = Repository::open(".")?;
+/// let fetcher = HttpFetcher::from_repo(&repo, None)?.header("Authorization", "Bearer ...");
+/// let filter = LfsFilter::open(&repo)?.missing_objects(MissingObject::Fetch(Box::new(fetcher)));
+/// let _reg = register_in_process_filter("lfs", true, filter)?;
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct HttpFetcher {
+    url: String,
+    headers: Vec<(String, String)>,
+    agent: ureq::Agent,
+}
+
+impl HttpFetcher {
+    /// Use the LFS server at `url`, e.g. `https://example.com/repo.git/info/lfs`.
+    pub fn new(url: &str) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(HTTP_TIMEOUT))
+            .build()
+            .into();
+        HttpFetcher {
+            url: url.trim_end_matches('/').to_string(),
+            headers: Vec::new(),
+            agent,
+        }
+    }
+
+    /// Use the LFS server git-lfs would use for `remote` (default:
+    /// `remote.lfsdefault`, then `origin`): `lfs.url`, then
+    /// `remote.<remote>.lfsurl`, then one derived from `remote.<remote>.url`.
+    pub fn from_repo(repo: &Repository, remote: Option<&str>) -> Result<Self, Error> {
+        let config = repo.config()?;
+        let remote = remote.map_or_else(|| default_remote(&config), str::to_string);
+        let url = endpoint(&config, &remote).ok_or_else(|| {
+            Error::from_str(&format!(
+                "no LFS endpoint for remote '{}': set lfs.url or remote.{}.lfsurl",
+                remote, remote
+            ))
+        })?;
+        Ok(HttpFetcher::new(&url))
+    }
+
+    /// Send `name: value` with every request, e.g. for authentication.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The LFS server URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Ask the server where to download `oid` from: the URL and headers of
+    /// its `download` action.
+    fn batch(&self, oid: &str, size: u64) -> Result<(String, Vec<(String, String)>), Error> {
+        let url = format!("{}/objects/batch", self.url);
+        let request = json!({
+            "operation": "download",
+            "transfers": ["basic"],
+            "objects": [{ "oid": oid, "size": size }],
+            "hash_algo": "sha256",
+        });
+        trace::event(|| format!("LFS batch download {} from {}", oid, url));
+
+        let mut builder = self
+            .agent
+            .post(&url)
+            .header("Accept", LFS_MEDIA_TYPE)
+            .header("Content-Type", LFS_MEDIA_TYPE);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        let mut response = builder
+            .send(request.to_string())
+            .map_err(|e| Error::from_str(&format!("LFS batch request to {} failed: {}", url, e)))?;
+        let status = response.status();
+        let body = response.body_mut().read_to_string().unwrap_or_default();
+        let body: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        if !status.is_success() {
+            return Err(Error::from_str(&format!(
+                "LFS batch request to {} failed: HTTP {}: {}",
+                url,
+                status.as_u16(),
+                body["message"].as_str().unwrap_or("no message")
+            )));
+        }
+
+        let object = body["objects"]
+            .as_array()
+            .and_then(|objects| objects.iter().find(|o| o["oid"] == oid))
+            .ok_or_else(|| Error::from_str(&format!("LFS server did not return object {}", oid)))?;
+        if !object["error"].is_null() {
+            return Err(Error::from_str(&format!(
+                "LFS server cannot provide object {}: {} {}",
+                oid,
+                object["error"]["code"],
+                object["error"]["message"].as_str().unwrap_or("")
+            )));
+        }
+        let download = &object["actions"]["download"];
+        let href = download["href"].as_str().ok_or_else(|| {
+            Error::from_str(&format!("LFS server returned no download for {}", oid))
+        })?;
+        let headers = download["header"]
+            .as_object()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok((href.to_string(), headers))
+    }
+}
+
+impl LfsFetcher for HttpFetcher {
+    fn fetch(&self, oid: &str, size: u64) -> Result<Vec<u8>, Error> {
+        let (href, headers) = self.batch(oid, size)?;
+
+        let start = std::time::Instant::now();
+        let mut builder = self.agent.get(&href);
+        for (name, value) in &headers {
+            builder = builder.header(name, value);
+        }
+        let mut response = builder
+            .call()
+            .map_err(|e| Error::from_str(&format!("LFS download of {} failed: {}", oid, e)))?;
+        if !response.status().is_success() {
+            return Err(Error::from_str(&format!(
+                "LFS download of {} failed: HTTP {}",
+                oid,
+                response.status().as_u16()
+            )));
+        }
+        let mut content = Vec::new();
+        response
+            .body_mut()
+            .as_reader()
+            .read_to_end(&mut content)
+            .map_err(|e| Error::from_str(&format!("LFS download of {} failed: {}", oid, e)))?;
+        trace::event(|| {
+            format!(
+                "LFS downloaded {} ({} bytes) in {:?}",
+                oid,
+                content.len(),
+                start.elapsed()
+            )
+        });
+        Ok(content)
+    }
+}
+
+/// The LFS endpoint for `remote`, resolved the way git-lfs does.
+fn endpoint(config: &Config, remote: &str) -> Option<String> {
+    if let Ok(url) = config.get_string("lfs.url") {
+        return Some(url);
+    }
+    if let Ok(url) = config.get_string(&format!("remote.{}.lfsurl", remote)) {
+        return Some(url);
+    }
+    derive_endpoint(&config.get_string(&format!("remote.{}.url", remote)).ok()?)
+}
+
+/// Derive the LFS endpoint from a git remote URL: `<repo>.git/info/lfs` over
+/// HTTPS. SSH remotes map to HTTPS on the same host, as git-lfs does when
+/// `git-lfs-authenticate` is not used.
+fn derive_endpoint(remote_url: &str) -> Option<String> {
+    let http = if remote_url.starts_with("https://") || remote_url.starts_with("http://") {
+        remote_url.to_string()
+    } else if let Some(rest) = remote_url.strip_prefix("ssh://") {
+        let rest = rest.split_once('@').map_or(rest, |(_, host)| host);
+        let (host, path) = rest.split_once('/')?;
+        let host = host.split_once(':').map_or(host, |(host, _port)| host);
+        format!("https://{}/{}", host, path)
+    } else if let Some((user_host, path)) = remote_url.split_once(':') {
+        // scp-like `user@host:path`; a plain local path has no host part
+        if user_host.contains('/') || path.starts_with("//") {
+            return None;
+        }
+        let host = user_host
+            .split_once('@')
+            .map_or(user_host, |(_, host)| host);
+        format!("https://{}/{}", host, path.trim_start_matches('/'))
+    } else {
+        return None;
+    };
+
+    let http = http.trim_end_matches('/');
+    if http.ends_with(".git") {
+        Some(format!("{}/info/lfs", http))
+    } else {
+        Some(format!("{}.git/info/lfs", http))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_derive_endpoint() {
+        let cases = [
+            (
+                "https://example.com/org/repo.git",
+                Some("https://example.com/org/repo.git/info/lfs"),
+            ),
+            (
+                "https://example.com/org/repo",
+                Some("https://example.com/org/repo.git/info/lfs"),
+            ),
+            (
+                "git@example.com:org/repo.git",
+                Some("https://example.com/org/repo.git/info/lfs"),
+            ),
+            (
+                "ssh://git@example.com:2222/org/repo",
+                Some("https://example.com/org/repo.git/info/lfs"),
+            ),
+            ("/srv/git/repo.git", None),
+            ("file:///srv/git/repo.git", None),
+        ];
+        for (remote, expected) in cases {
+            assert_eq!(derive_endpoint(remote).as_deref(), expected, "{}", remote);
+        }
+    }
+
+    #[test]
+    fn test_endpoint_precedence() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        assert!(HttpFetcher::from_repo(&repo, None).is_err());
+
+        config
+            .set_str("remote.origin.url", "https://example.com/repo")
+            .unwrap();
+        let url = |remote| {
+            HttpFetcher::from_repo(&repo, remote)
+                .unwrap()
+                .url()
+                .to_string()
+        };
+        assert_eq!(url(None), "https://example.com/repo.git/info/lfs");
+
+        config
+            .set_str("remote.origin.lfsurl", "https://lfs.example.com/repo/")
+            .unwrap();
+        assert_eq!(url(None), "https://lfs.example.com/repo");
+
+        config
+            .set_str("remote.mirror.url", "git@mirror.example.com:repo.git")
+            .unwrap();
+        assert_eq!(
+            url(Some("mirror")),
+            "https://mirror.example.com/repo.git/info/lfs"
+        );
+
+        config.set_str("lfs.url", "https://override/lfs").unwrap();
+        assert_eq!(url(Some("mirror")), "https://override/lfs");
+    }
+}
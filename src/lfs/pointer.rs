@@ -0,0 +1,393 @@
+//! The Git LFS pointer file format.
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Spec version written on the first line of every pointer.
+pub const VERSION: &str = "https://git-lfs.github.com/spec/v1";
+
+/// Older names of the v1 spec that git-lfs still reads.
+const VERSION_ALIASES: &[&str] = &["https://hawser.github.com/spec/v1"];
+
+/// Pointers are small; anything this size or larger is content.
+pub const MAX_POINTER_SIZE: usize = 1024;
+
+/// A Git LFS pointer: the blob committed in place of the real content.
+///
+/// [`parse`](Self::parse) accepts anything git-lfs reads, including
+/// variations git-lfs never writes (CRLF line endings, a missing final
+/// newline, the legacy `hawser` version URL, zero-padded sizes).
+/// [`parse_canonical`](Self::parse_canonical) accepts exactly what
+/// [`Display`](fmt::Display) writes, which is byte-identical to git-lfs output.
+///
+/// # Example
+///
+/// ```
+/// use git2_process_filter::lfs::Pointer;
+///
+/// let pointer = Pointer::from_content(b"hello\n");
+/// let text = pointer.to_string();
+/// assert_eq!(Pointer::parse_canonical(text.as_bytes())?, pointer);
+///
+/// // Readable by git-lfs, but not what it writes
+/// let crlf = text.replace('\n', "\r\n");
+/// assert_eq!(Pointer::parse(crlf.as_bytes())?, pointer);
+/// assert!(Pointer::parse_canonical(crlf.as_bytes()).is_err());
+/// # Ok::<(), git2_process_filter::lfs::PointerError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pointer {
+    /// Hex SHA-256 of the content.
+    pub oid: String,
+    /// Size of the content in bytes.
+    pub size: u64,
+    /// Extensions that transformed the content, in priority order.
+    pub extensions: Vec<Extension>,
+}
+
+/// An `ext-<priority>-<name> sha256:<oid>` line: a git-lfs extension that
+/// transformed the content before it was stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    /// Extension name.
+    pub name: String,
+    /// Order in which the extension ran, 0 through 9.
+    pub priority: u8,
+    /// Hex SHA-256 of the content before the extension ran.
+    pub oid: String,
+}
+
+/// Why data is not a valid LFS pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointerError {
+    /// Empty data.
+    Empty,
+    /// At least [`MAX_POINTER_SIZE`] bytes.
+    TooLarge(usize),
+    /// Not valid UTF-8.
+    NotUtf8,
+    /// The first line is not a `version` line.
+    MissingVersion,
+    /// The `version` is not a known LFS spec.
+    UnsupportedVersion(String),
+    /// A line that is not `<key> <value>` with a valid key.
+    MalformedLine {
+        /// 1-based line number.
+        line: usize,
+        /// The offending line.
+        text: String,
+    },
+    /// A key that does not sort after the key before it, or appears twice.
+    KeyOrder {
+        /// 1-based line number.
+        line: usize,
+        /// The misplaced key.
+        key: String,
+        /// The key on the line before.
+        previous: String,
+    },
+    /// A required key is missing.
+    MissingKey(&'static str),
+    /// An `oid` (or extension oid) that is not `sha256:` and 64 lowercase hex digits.
+    InvalidOid(String),
+    /// A `size` that is not a non-negative integer.
+    InvalidSize(String),
+    /// An extension line with a bad key or duplicate priority.
+    InvalidExtension(String),
+    /// A valid pointer that is not byte-identical to what git-lfs writes.
+    NonCanonical,
+}
+
+impl fmt::Display for PointerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointerError::Empty => write!(f, "empty pointer"),
+            PointerError::TooLarge(size) => write!(
+                f,
+                "{} bytes is too large for a pointer (limit {})",
+                size, MAX_POINTER_SIZE
+            ),
+            PointerError::NotUtf8 => write!(f, "pointer is not valid UTF-8"),
+            PointerError::MissingVersion => write!(f, "first line is not a version line"),
+            PointerError::UnsupportedVersion(v) => write!(f, "unsupported version '{}'", v),
+            PointerError::MalformedLine { line, text } => {
+                write!(f, "line {}: malformed line '{}'", line, text)
+            }
+            PointerError::KeyOrder {
+                line,
+                key,
+                previous,
+            } => write!(
+                f,
+                "line {}: key '{}' must sort after '{}'",
+                line, key, previous
+            ),
+            PointerError::MissingKey(key) => write!(f, "missing '{}'", key),
+            PointerError::InvalidOid(oid) => write!(f, "invalid oid '{}'", oid),
+            PointerError::InvalidSize(size) => write!(f, "invalid size '{}'", size),
+            PointerError::InvalidExtension(ext) => write!(f, "invalid extension '{}'", ext),
+            PointerError::NonCanonical => {
+                write!(f, "pointer is valid but not in canonical form")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PointerError {}
+
+impl From<PointerError> for git2::Error {
+    fn from(e: PointerError) -> Self {
+        git2::Error::from_str(&format!("invalid LFS pointer: {}", e))
+    }
+}
+
+impl Pointer {
+    /// The pointer git-lfs writes for `content`.
+    pub fn from_content(content: &[u8]) -> Pointer {
+        Pointer {
+            oid: format!("{:x}", Sha256::digest(content)),
+            size: content.len() as u64,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Parse a pointer, accepting everything git-lfs reads.
+    pub fn parse(data: &[u8]) -> Result<Pointer, PointerError> {
+        if data.is_empty() {
+            return Err(PointerError::Empty);
+        }
+        if data.len() >= MAX_POINTER_SIZE {
+            return Err(PointerError::TooLarge(data.len()));
+        }
+        let text = std::str::from_utf8(data).map_err(|_| PointerError::NotUtf8)?;
+        let text = text.strip_suffix('\n').unwrap_or(text);
+
+        let mut lines = text
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .enumerate()
+            .map(|(i, line)| (i + 1, line));
+
+        let (_, first) = lines.next().ok_or(PointerError::MissingVersion)?;
+        let version = first
+            .strip_prefix("version ")
+            .ok_or(PointerError::MissingVersion)?;
+        if version != VERSION && !VERSION_ALIASES.contains(&version) {
+            return Err(PointerError::UnsupportedVersion(version.to_string()));
+        }
+
+        let mut oid = None;
+        let mut size = None;
+        let mut extensions: Vec<Extension> = Vec::new();
+        let mut previous = "version";
+        for (line, text) in lines {
+            let malformed = || PointerError::MalformedLine {
+                line,
+                text: text.to_string(),
+            };
+            let (key, value) = text.split_once(' ').ok_or_else(malformed)?;
+            let valid_key = !key.is_empty()
+                && key
+                    .bytes()
+                    .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-'));
+            if !valid_key {
+                return Err(malformed());
+            }
+            if previous != "version" && key <= previous {
+                return Err(PointerError::KeyOrder {
+                    line,
+                    key: key.to_string(),
+                    previous: previous.to_string(),
+                });
+            }
+            previous = key;
+
+            match key {
+                "oid" => oid = Some(parse_oid(value)?),
+                "size" => {
+                    let valid = !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit());
+                    let parsed = value.parse().ok().filter(|_| valid);
+                    size = Some(parsed.ok_or_else(|| PointerError::InvalidSize(value.into()))?);
+                }
+                _ if key.starts_with("ext-") => {
+                    let ext = parse_extension(key, value)?;
+                    if extensions.iter().any(|e| e.priority == ext.priority) {
+                        return Err(PointerError::InvalidExtension(text.to_string()));
+                    }
+                    extensions.push(ext);
+                }
+                // Keys from later spec revisions are ignored, as git-lfs does
+                _ => {}
+            }
+        }
+
+        Ok(Pointer {
+            oid: oid.ok_or(PointerError::MissingKey("oid"))?,
+            size: size.ok_or(PointerError::MissingKey("size"))?,
+            extensions,
+        })
+    }
+
+    /// Parse a pointer and require it to be byte-identical to what git-lfs
+    /// writes, e.g. to catch pointers committed by buggy clients.
+    pub fn parse_canonical(data: &[u8]) -> Result<Pointer, PointerError> {
+        let pointer = Pointer::parse(data)?;
+        if pointer.to_string().as_bytes() != data {
+            return Err(PointerError::NonCanonical);
+        }
+        Ok(pointer)
+    }
+}
+
+fn parse_oid(value: &str) -> Result<String, PointerError> {
+    let hex = value
+        .strip_prefix("sha256:")
+        .filter(|hex| {
+            hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        })
+        .ok_or_else(|| PointerError::InvalidOid(value.to_string()))?;
+    Ok(hex.to_string())
+}
+
+/// Parse `ext-<priority>-<name> sha256:<oid>`.
+fn parse_extension(key: &str, value: &str) -> Result<Extension, PointerError> {
+    let invalid = || PointerError::InvalidExtension(format!("{} {}", key, value));
+    let rest = key.strip_prefix("ext-").ok_or_else(invalid)?;
+    let (priority, name) = rest.split_once('-').ok_or_else(invalid)?;
+    if priority.len() != 1 || name.is_empty() {
+        return Err(invalid());
+    }
+    let priority = priority.parse().map_err(|_| invalid())?;
+    Ok(Extension {
+        name: name.to_string(),
+        priority,
+        oid: parse_oid(value)?,
+    })
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version {}", VERSION)?;
+        for ext in &self.extensions {
+            writeln!(f, "ext-{}-{} sha256:{}", ext.priority, ext.name, ext.oid)?;
+        }
+        write!(f, "oid sha256:{}\nsize {}\n", self.oid, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointer(oid: &str, size: u64) -> Pointer {
+        Pointer {
+            oid: oid.to_string(),
+            size,
+            extensions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_pointer_roundtrip() {
+        let p = pointer(&"a".repeat(64), 12345);
+        let text = p.to_string();
+        assert_eq!(Pointer::parse_canonical(text.as_bytes()), Ok(p.clone()));
+
+        let with_ext = Pointer {
+            extensions: vec![
+                Extension {
+                    name: "foo".to_string(),
+                    priority: 0,
+                    oid: "b".repeat(64),
+                },
+                Extension {
+                    name: "bar".to_string(),
+                    priority: 1,
+                    oid: "c".repeat(64),
+                },
+            ],
+            ..p
+        };
+        let text = with_ext.to_string();
+        assert!(text.contains("\next-0-foo sha256:bbbb"));
+        assert_eq!(Pointer::parse_canonical(text.as_bytes()), Ok(with_ext));
+    }
+
+    #[test]
+    fn test_pointer_non_canonical() {
+        let canonical = pointer(&"a".repeat(64), 5).to_string();
+        let variants = [
+            canonical.replace('\n', "\r\n"),
+            canonical.trim_end().to_string(),
+            canonical.replace("size 5", "size 005"),
+            canonical.replace("git-lfs.github.com", "hawser.github.com"),
+            canonical.replace("size 5", "size 5\nzzz later"),
+        ];
+        for text in &variants {
+            assert!(Pointer::parse(text.as_bytes()).is_ok(), "{:?}", text);
+            assert_eq!(
+                Pointer::parse_canonical(text.as_bytes()),
+                Err(PointerError::NonCanonical),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_pointer_errors() {
+        let oid = "a".repeat(64);
+        let cases: Vec<(String, PointerError)> = vec![
+            (String::new(), PointerError::Empty),
+            ("hello\n".to_string(), PointerError::MissingVersion),
+            (
+                "version https://example.com/v9\n".to_string(),
+                PointerError::UnsupportedVersion("https://example.com/v9".to_string()),
+            ),
+            (
+                format!("version {}\nsize 5\noid sha256:{}\n", VERSION, oid),
+                PointerError::KeyOrder {
+                    line: 3,
+                    key: "oid".to_string(),
+                    previous: "size".to_string(),
+                },
+            ),
+            (
+                format!("version {}\noid sha256:{}\n", VERSION, oid),
+                PointerError::MissingKey("size"),
+            ),
+            (
+                format!("version {}\noid md5:abc\nsize 5\n", VERSION),
+                PointerError::InvalidOid("md5:abc".to_string()),
+            ),
+            (
+                format!("version {}\noid sha256:{}\nsize -5\n", VERSION, oid),
+                PointerError::InvalidSize("-5".to_string()),
+            ),
+            (
+                format!("version {}\nOid sha256:{}\nsize 5\n", VERSION, oid),
+                PointerError::MalformedLine {
+                    line: 2,
+                    text: format!("Oid sha256:{}", oid),
+                },
+            ),
+            (
+                format!(
+                    "version {}\next-10-foo sha256:{}\noid sha256:{}\nsize 5\n",
+                    VERSION, oid, oid
+                ),
+                PointerError::InvalidExtension(format!("ext-10-foo sha256:{}", oid)),
+            ),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(Pointer::parse(text.as_bytes()), Err(expected), "{:?}", text);
+        }
+
+        let large = vec![b'a'; MAX_POINTER_SIZE];
+        assert_eq!(
+            Pointer::parse(&large),
+            Err(PointerError::TooLarge(MAX_POINTER_SIZE))
+        );
+        assert_eq!(Pointer::parse(&[0xff, 0xfe]), Err(PointerError::NotUtf8));
+    }
+}
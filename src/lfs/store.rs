@@ -0,0 +1,170 @@
+//! The local LFS object store.
+
+use git2::{Error, Repository};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Objects stored under `<root>/objects/aa/bb/<oid>`, where `<root>` is
+/// `lfs.storage` (relative to the git directory) or `.git/lfs`.
+pub(crate) struct LfsStore {
+    root: PathBuf,
+    /// Other stores searched for objects, like git-lfs does: `.git/lfs` when
+    /// `lfs.storage` moved the store, and the stores next to alternate object
+    /// directories.
+    fallbacks: Vec<PathBuf>,
+}
+
+impl LfsStore {
+    pub(crate) fn open(repo: &Repository) -> Result<Self, Error> {
+        let git_dir = repo.commondir();
+        let default = git_dir.join("lfs");
+        let root = match repo.config()?.get_path("lfs.storage") {
+            Ok(path) if !path.as_os_str().is_empty() => git_dir.join(path),
+            _ => default.clone(),
+        };
+
+        let mut fallbacks = Vec::new();
+        if root != default {
+            fallbacks.push(default);
+        }
+        let objects = git_dir.join("objects");
+        if let Ok(alternates) = fs::read_to_string(objects.join("info/alternates")) {
+            for line in alternates.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // `<alternate>` is an objects directory; its LFS store sits beside it
+                let alternate = objects.join(line);
+                if let Some(git_dir) = alternate.parent() {
+                    fallbacks.push(git_dir.join("lfs"));
+                }
+            }
+        }
+
+        Ok(LfsStore { root, fallbacks })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn object_path(&self, oid: &str) -> PathBuf {
+        Self::path_in(&self.root, oid)
+    }
+
+    fn path_in(root: &Path, oid: &str) -> PathBuf {
+        root.join("objects")
+            .join(&oid[0..2])
+            .join(&oid[2..4])
+            .join(oid)
+    }
+
+    /// Locate object `oid` in this store or a fallback store.
+    pub(crate) fn find(&self, oid: &str) -> Option<PathBuf> {
+        std::iter::once(&self.root)
+            .chain(&self.fallbacks)
+            .map(|root| Self::path_in(root, oid))
+            .find(|path| path.is_file())
+    }
+
+    /// Store `content` as object `oid`. Objects are written to a temporary
+    /// file first, so readers never see a partial object.
+    pub(crate) fn insert(&self, oid: &str, content: &[u8]) -> io::Result<()> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = self.object_path(oid);
+        if path.exists() {
+            return Ok(());
+        }
+        let tmp_dir = self.root.join("tmp");
+        fs::create_dir_all(&tmp_dir)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = tmp_dir.join(format!(
+            "{}-{}-{}",
+            oid,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lfs_storage() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let git_dir = repo.path().to_path_buf();
+        assert_eq!(LfsStore::open(&repo).unwrap().root(), git_dir.join("lfs"));
+
+        let mut config = repo.config().unwrap();
+        config.set_str("lfs.storage", "big-files").unwrap();
+        assert_eq!(
+            LfsStore::open(&repo).unwrap().root(),
+            git_dir.join("big-files")
+        );
+
+        let shared = td.path().join("shared");
+        config
+            .set_str("lfs.storage", shared.to_str().unwrap())
+            .unwrap();
+        let store = LfsStore::open(&repo).unwrap();
+        assert_eq!(store.root(), shared);
+
+        let oid = "ab".repeat(32);
+        store.insert(&oid, b"data").unwrap();
+        assert_eq!(
+            store.object_path(&oid),
+            shared.join("objects/ab/ab").join(&oid)
+        );
+        assert_eq!(fs::read(store.object_path(&oid)).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_find_in_fallback_stores() {
+        let td = TempDir::new().unwrap();
+        let shared = Repository::init(td.path().join("shared")).unwrap();
+        let repo = Repository::init(td.path().join("repo")).unwrap();
+        fs::write(
+            repo.path().join("objects/info/alternates"),
+            format!("{}\n", shared.path().join("objects").display()),
+        )
+        .unwrap();
+        repo.config()
+            .unwrap()
+            .set_str("lfs.storage", "moved")
+            .unwrap();
+
+        let in_alternate = "aa".repeat(32);
+        LfsStore::open(&shared)
+            .unwrap()
+            .insert(&in_alternate, b"shared")
+            .unwrap();
+        let in_default = "bb".repeat(32);
+        let default = repo.path().join("lfs/objects/bb/bb");
+        fs::create_dir_all(&default).unwrap();
+        fs::write(default.join(&in_default), b"old").unwrap();
+
+        let store = LfsStore::open(&repo).unwrap();
+        assert_eq!(
+            fs::read(store.find(&in_alternate).unwrap()).unwrap(),
+            b"shared"
+        );
+        assert_eq!(fs::read(store.find(&in_default).unwrap()).unwrap(), b"old");
+        assert_eq!(store.find(&"cc".repeat(32)), None);
+    }
+}
@@ -0,0 +1,459 @@
+//! Standalone custom transfer agents (`lfs.customtransfer.<name>.*`).
+//!
+//! An agent is a program that moves LFS objects to and from storage git-lfs
+//! does not know about, speaking git-lfs's JSON-lines protocol over stdin and
+//! stdout. Agents are started like filter processes: in the working tree,
+//! with the caller's environment, stderr forwarded and collected, and
+//! stopped by closing stdin with a grace period before being killed.
+
+use super::fetch::default_remote;
+use super::LfsFetcher;
+use crate::process::{ShutdownReport, Spawned, SHUTDOWN_GRACE};
+use crate::{trace, ProcessFilter, DEFAULT_TIMEOUT};
+use git2::{Error, Repository};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ChildStdin;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+
+/// Why a transfer on a running agent failed.
+enum TransferError {
+    /// The agent reported an `error` for the object, and is still usable.
+    Reported(Error),
+    /// The agent crashed, hung or broke the protocol.
+    Agent(Error),
+}
+
+impl TransferError {
+    fn into_error(self) -> Error {
+        match self {
+            TransferError::Reported(e) | TransferError::Agent(e) => e,
+        }
+    }
+}
+
+impl From<Error> for TransferError {
+    fn from(e: Error) -> Self {
+        TransferError::Agent(e)
+    }
+}
+
+/// An agent process initialized for one operation (`download` or `upload`).
+struct AgentProcess {
+    label: String,
+    /// `None` once stdin has been closed to ask the agent to exit.
+    stdin: Option<BufWriter<ChildStdin>>,
+    /// Lines the agent writes, read by a thread so waits can time out.
+    replies: Receiver<io::Result<String>>,
+    process: Spawned,
+}
+
+impl AgentProcess {
+    fn start(
+        agent: &TransferAgent,
+        operation: &'static str,
+        stderr_log: Arc<Mutex<Vec<u8>>>,
+    ) -> Result<Self, Error> {
+        let mut process = Spawned::start(
+            &agent.program,
+            &agent.args,
+            agent.workdir.as_deref(),
+            stderr_log,
+        )
+        .map_err(|e| {
+            Error::from_str(&format!(
+                "failed to start transfer agent '{}': {}",
+                agent.name, e
+            ))
+        })?;
+        let stdin = process.child.stdin.take().expect("stdin is piped");
+        let stdout = process.child.stdout.take().expect("stdout is piped");
+
+        let (sender, replies) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut agent_process = AgentProcess {
+            label: format!("transfer agent '{}'", agent.name),
+            stdin: Some(BufWriter::new(stdin)),
+            replies,
+            process,
+        };
+        let reply = agent_process.exchange(&json!({
+            "event": "init",
+            "operation": operation,
+            "remote": agent.remote,
+            "concurrent": false,
+            "concurrenttransfers": 1,
+        }))?;
+        check_error(&reply).map_err(TransferError::into_error)?;
+        Ok(agent_process)
+    }
+
+    fn send(&mut self, message: &Value) -> Result<(), Error> {
+        let line = message.to_string();
+        trace::event(|| format!("{}> {}", self.label, line));
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| Error::from_str("stdin already closed"))?;
+        writeln!(stdin, "{}", line)
+            .and_then(|_| stdin.flush())
+            .map_err(|e| Error::from_str(&format!("failed to write to {}: {}", self.label, e)))
+    }
+
+    fn recv(&mut self) -> Result<Value, Error> {
+        let line = match self.replies.recv_timeout(DEFAULT_TIMEOUT) {
+            Ok(Ok(line)) => line,
+            Ok(Err(e)) => {
+                return Err(Error::from_str(&format!(
+                    "failed to read from {}: {}",
+                    self.label, e
+                )))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::from_str(&format!(
+                    "{} timed out after {:?}",
+                    self.label, DEFAULT_TIMEOUT
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::from_str(&format!("{} exited", self.label)))
+            }
+        };
+        trace::event(|| format!("{}< {}", self.label, line));
+        serde_json::from_str(&line).map_err(|e| {
+            Error::from_str(&format!(
+                "{} sent invalid JSON '{}': {}",
+                self.label, line, e
+            ))
+        })
+    }
+
+    fn exchange(&mut self, message: &Value) -> Result<Value, Error> {
+        self.send(message)?;
+        self.recv()
+    }
+
+    /// Send a `download` or `upload` request and wait for its `complete`
+    /// event, skipping progress events.
+    fn transfer(&mut self, request: &Value) -> Result<Value, TransferError> {
+        self.send(request)?;
+        loop {
+            let reply = self.recv()?;
+            match reply["event"].as_str() {
+                Some("progress") => continue,
+                Some("complete") if reply["oid"] == request["oid"] => {
+                    check_error(&reply)?;
+                    return Ok(reply);
+                }
+                _ => {
+                    return Err(TransferError::Agent(Error::from_str(&format!(
+                        "{} sent unexpected message {}",
+                        self.label, reply
+                    ))))
+                }
+            }
+        }
+    }
+
+    fn shutdown(&mut self) -> ShutdownReport {
+        let _ = self.send(&json!({ "event": "terminate" }));
+        // Closing stdin as well covers agents that ignore `terminate`
+        drop(self.stdin.take());
+        let (status, killed) = self.process.stop(SHUTDOWN_GRACE);
+        ShutdownReport {
+            stderr: Vec::new(),
+            status,
+            killed,
+        }
+    }
+}
+
+/// Turn an `error` object in an agent reply into an `Err`.
+fn check_error(reply: &Value) -> Result<(), TransferError> {
+    if reply["error"].is_null() {
+        return Ok(());
+    }
+    Err(TransferError::Reported(Error::from_str(&format!(
+        "transfer failed: {} {}",
+        reply["error"]["code"],
+        reply["error"]["message"].as_str().unwrap_or("")
+    ))))
+}
+
+/// A git-lfs standalone custom transfer agent, configured with
+/// `lfs.customtransfer.<name>.path`, `.args` and `.direction`.
+///
+/// It is started on first use, once per operation, and reused for every
+/// object until [`shutdown`](Self::shutdown) or drop. An agent that crashes
+/// or does not answer within the filter timeout is stopped and started again
+/// for the next object.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::Repository;
+/// use git2_process_filter::lfs::{LfsFilter, MissingObject, TransferAgent};
+/// use git2_process_filter::register_in_process_filter;
+///
+/// let repo = Repository::open(".")?;
+/// // lfs.standalonetransferagent = nfs
+/// // lfs.customtransfer.nfs.path = /usr/local/bin/lfs-nfs-agent
+/// if let Some(agent) = TransferAgent::standalone(&repo, None)? {
+///     let filter = LfsFilter::open(&repo)?.missing_objects(MissingObject::Fetch(Box::new(agent)));
+///     let _reg = register_in_process_filter("lfs", true, filter)?;
+/// }
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct TransferAgent {
+    name: String,
+    program: String,
+    args: Vec<String>,
+    download: bool,
+    upload: bool,
+    remote: String,
+    workdir: Option<PathBuf>,
+    agents: Mutex<Vec<(&'static str, AgentProcess)>>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TransferAgent {
+    /// The agent configured as `lfs.customtransfer.<name>.*`.
+    pub fn from_config(repo: &Repository, name: &str) -> Result<Self, Error> {
+        let config = repo.config()?;
+        let key = |field: &str| format!("lfs.customtransfer.{}.{}", name, field);
+
+        let program = config
+            .get_string(&key("path"))
+            .ok()
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| Error::from_str(&format!("{} is not set", key("path"))))?;
+        let args = config.get_string(&key("args")).unwrap_or_default();
+        let (first, mut args) = ProcessFilter::parse_command(&args, "");
+        if !first.is_empty() {
+            args.insert(0, first);
+        }
+        let direction = config
+            .get_string(&key("direction"))
+            .unwrap_or_else(|_| "both".to_string());
+        let (download, upload) = match direction.as_str() {
+            "both" => (true, true),
+            "download" => (true, false),
+            "upload" => (false, true),
+            other => {
+                return Err(Error::from_str(&format!(
+                    "invalid {} '{}'",
+                    key("direction"),
+                    other
+                )))
+            }
+        };
+
+        Ok(TransferAgent {
+            name: name.to_string(),
+            program,
+            args,
+            download,
+            upload,
+            remote: default_remote(&config),
+            workdir: repo.workdir().map(Path::to_path_buf),
+            agents: Mutex::new(Vec::new()),
+            stderr: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// The standalone agent git-lfs would use for `remote` (default:
+    /// `remote.lfsdefault`, then `origin`), from
+    /// `remote.<remote>.standalonetransferagent` or
+    /// `lfs.standalonetransferagent`. `None` if neither is set.
+    pub fn standalone(repo: &Repository, remote: Option<&str>) -> Result<Option<Self>, Error> {
+        let config = repo.config()?;
+        let remote = remote.map_or_else(|| default_remote(&config), str::to_string);
+        let name = config
+            .get_string(&format!("remote.{}.standalonetransferagent", remote))
+            .or_else(|_| config.get_string("lfs.standalonetransferagent"));
+        match name {
+            Ok(name) if !name.is_empty() => {
+                let mut agent = TransferAgent::from_config(repo, &name)?;
+                agent.remote = remote;
+                Ok(Some(agent))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Run one transfer on the agent for `operation`, starting it if needed.
+    fn transfer(&self, operation: &'static str, request: Value) -> Result<Value, Error> {
+        let allowed = match operation {
+            "download" => self.download,
+            _ => self.upload,
+        };
+        if !allowed {
+            return Err(Error::from_str(&format!(
+                "transfer agent '{}' does not support {}",
+                self.name, operation
+            )));
+        }
+
+        let mut agents = self.agents.lock().unwrap_or_else(|e| e.into_inner());
+        let index = match agents.iter().position(|(op, _)| *op == operation) {
+            Some(index) => index,
+            None => {
+                let agent = AgentProcess::start(self, operation, Arc::clone(&self.stderr))?;
+                agents.push((operation, agent));
+                agents.len() - 1
+            }
+        };
+
+        let result = agents[index].1.transfer(&request).map_err(|e| match e {
+            TransferError::Reported(e) => e,
+            TransferError::Agent(e) => {
+                let (_, mut agent) = agents.remove(index);
+                eprintln!(
+                    "[git2-process-filter] warning: {}: {}; restarting it for the next object",
+                    agent.label,
+                    e.message()
+                );
+                agent.shutdown();
+                e
+            }
+        });
+        result.map_err(|e| {
+            Error::from_str(&format!(
+                "{} of LFS object {} through transfer agent '{}' failed: {}",
+                operation,
+                request["oid"].as_str().unwrap_or(""),
+                self.name,
+                e.message()
+            ))
+        })
+    }
+
+    /// Store the object at `path` (e.g. in the local LFS store) through the agent.
+    pub fn upload(&self, oid: &str, size: u64, path: &Path) -> Result<(), Error> {
+        self.transfer(
+            "upload",
+            json!({
+                "event": "upload",
+                "oid": oid,
+                "size": size,
+                "path": path,
+                "action": null,
+            }),
+        )
+        .map(|_| ())
+    }
+
+    /// Stop the agents, reporting how they exited and what they wrote to stderr.
+    pub fn shutdown(&self) -> ShutdownReport {
+        let agents = std::mem::take(&mut *self.agents.lock().unwrap_or_else(|e| e.into_inner()));
+        let report = agents
+            .into_iter()
+            .map(|(_, mut agent)| agent.shutdown())
+            .fold(ShutdownReport::default(), ShutdownReport::merge);
+        ShutdownReport {
+            stderr: std::mem::take(&mut *self.stderr.lock().unwrap_or_else(|e| e.into_inner())),
+            ..report
+        }
+    }
+}
+
+impl LfsFetcher for TransferAgent {
+    fn fetch(&self, oid: &str, size: u64) -> Result<Vec<u8>, Error> {
+        let reply = self.transfer(
+            "download",
+            json!({
+                "event": "download",
+                "oid": oid,
+                "size": size,
+                "action": null,
+            }),
+        )?;
+        let path = reply["path"].as_str().ok_or_else(|| {
+            Error::from_str(&format!(
+                "transfer agent '{}' completed {} without a path",
+                self.name, oid
+            ))
+        })?;
+        let content = std::fs::read(path).map_err(|e| {
+            Error::from_str(&format!(
+                "failed to read {} downloaded by transfer agent '{}': {}",
+                path, self.name, e
+            ))
+        })?;
+        // The file is handed over to us, as git-lfs moves it into its store
+        let _ = std::fs::remove_file(path);
+        Ok(content)
+    }
+}
+
+impl Drop for TransferAgent {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_transfer_agent_config() {
+        let td = TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        assert!(TransferAgent::standalone(&repo, None).unwrap().is_none());
+
+        config
+            .set_str("lfs.standalonetransferagent", "nfs")
+            .unwrap();
+        let err = TransferAgent::standalone(&repo, None).err().unwrap();
+        assert_eq!(err.message(), "lfs.customtransfer.nfs.path is not set");
+
+        config
+            .set_str("lfs.customtransfer.nfs.path", "/opt/agent")
+            .unwrap();
+        config
+            .set_str("lfs.customtransfer.nfs.args", "--root '/mnt/lfs share' -v")
+            .unwrap();
+        config
+            .set_str("lfs.customtransfer.nfs.direction", "download")
+            .unwrap();
+        let agent = TransferAgent::standalone(&repo, None).unwrap().unwrap();
+        assert_eq!(agent.program, "/opt/agent");
+        assert_eq!(agent.args, vec!["--root", "/mnt/lfs share", "-v"]);
+        assert_eq!(agent.remote, "origin");
+
+        // Refused before any process is started
+        let err = agent
+            .upload(&"a".repeat(64), 1, Path::new("/x"))
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "transfer agent 'nfs' does not support upload"
+        );
+
+        config
+            .set_str("remote.backup.standalonetransferagent", "other")
+            .unwrap();
+        config
+            .set_str("lfs.customtransfer.other.path", "/opt/other")
+            .unwrap();
+        let agent = TransferAgent::standalone(&repo, Some("backup"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (agent.name.as_str(), agent.remote.as_str()),
+            ("other", "backup")
+        );
+    }
+}
@@ -3,6 +3,10 @@
 //! This crate provides [`register_process_filter`], which reads filter commands
 //! from git config and shells out to them for clean/smudge operations.
 //!
+//! If `filter.<name>.process` is configured, a single long-running process is
+//! started instead and fed every blob over git's long-running filter protocol,
+//! just like git does for `git-lfs filter-process`.
+//!
 //! # Example
 //!
 //! ```no_run
@@ -21,24 +25,88 @@
 //! # Ok::<(), git2::Error>(())
 //! ```
 
+mod chain;
+mod clean_cache;
+mod encoding;
+pub mod git_crypt;
+mod in_process;
+pub mod lfs;
+mod merge;
+mod observer;
+mod pkt_line;
+mod process;
+mod roundtrip;
+pub mod server;
+mod smudge_cache;
+mod stats;
+mod textconv;
+pub mod trace;
+
+pub use chain::FilterChain;
 use git2::{
     filter_priority, filter_register, Error, Filter, FilterMode, FilterRegistration, FilterSource,
 };
+pub use in_process::InProcessFilter;
+pub use merge::{MergeDriver, MergeResult};
+pub use observer::{DriverKind, FilterEvent, FilterObserver, FilterRequest};
+use process::{Outcome, ProcessDriver};
+pub use process::{ProcessCapabilities, RequestMetadata, ShutdownReport};
+pub use stats::{FailureKind, FilterStats, LatencyHistogram, ModeStats};
+use std::borrow::Cow;
 use std::io::{Read, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+pub use textconv::Textconv;
 
 /// Default timeout for filter commands (5 minutes).
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Maximum buffer size before switching to streaming (64KB).
 const STREAM_THRESHOLD: usize = 64 * 1024;
 
-/// A filter that shells out to external commands configured in git config.
+/// How a registered filter transforms content.
+enum Driver {
+    /// Single-shot `filter.<name>.clean`/`smudge` commands, spawned per blob.
+    Commands { clean: String, smudge: String },
+    /// Long-running process from `filter.<name>.process`, with the
+    /// `clean`/`smudge` commands used when it does not advertise a direction
+    /// or cannot be started.
+    Process {
+        process: Arc<ProcessDriver>,
+        clean: String,
+        smudge: String,
+    },
+    /// Rust code registered with [`register_in_process_filter`].
+    InProcess(Box<dyn InProcessFilter>),
+    /// Stages of a [`FilterChain`], in clean order.
+    Chain(Vec<ProcessFilter>),
+}
+
+/// A filter that shells out to external commands configured in git config,
+/// or runs an in-process Rust filter through the same machinery.
 struct ProcessFilter {
-    clean_cmd: String,
-    smudge_cmd: String,
+    name: String,
+    driver: Driver,
+    /// `filter.<name>.required`: fail instead of passing content through
+    /// when the long-running process cannot filter it.
+    required: bool,
+    /// `filter.<name>.verifypointer`: the LFS store clean output must
+    /// reference, for drivers that are expected to write LFS pointers.
+    verify_pointers: Option<lfs::LfsStore>,
+    /// `filter.<name>.smudgecache`: smudge output cached by input.
+    smudge_cache: Option<smudge_cache::SmudgeCache>,
+    /// `filter.<name>.cleancache`: clean output cached by stat data.
+    clean_cache: Option<clean_cache::CleanCache>,
+    /// `filter.<name>.verifyroundtrip`: check that clean output smudges back.
+    verify_roundtrip: bool,
+    /// `filter.<name>.missing` on a long-running process: LFS pointers it
+    /// smudges to are replaced with their objects, like the built-in smudge.
+    resolve_pointers: Option<lfs::LfsFilter>,
+    stats: Arc<stats::Recorder>,
+    observers: observer::Observers,
 }
 
 impl ProcessFilter {
@@ -85,6 +153,48 @@ impl ProcessFilter {
         (program, args)
     }
 
+    /// Expand `%<c>` placeholders in one argument of a parsed command, like
+    /// git does for merge drivers: `%%` is a literal `%`, and unknown
+    /// placeholders are left as they are.
+    fn expand_placeholders(arg: &str, values: &[(char, &str)]) -> String {
+        let mut expanded = String::with_capacity(arg.len());
+        let mut chars = arg.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some(key) => match values.iter().find(|(k, _)| *k == key) {
+                    Some((_, value)) => expanded.push_str(value),
+                    None => {
+                        expanded.push('%');
+                        expanded.push(key);
+                    }
+                },
+                None => expanded.push('%'),
+            }
+        }
+        expanded
+    }
+
+    /// Handle a blob the long-running process or in-process filter could not
+    /// filter: fail if the filter is required, otherwise pass the content
+    /// through unchanged.
+    fn decline(&self, reason: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+        stats::note_declined();
+        if self.required {
+            stats::note_failure(FailureKind::Declined);
+            return Err(Error::from_str(reason));
+        }
+        eprintln!(
+            "[git2-process-filter] warning: {}; passing content through",
+            reason
+        );
+        Ok(input.to_vec())
+    }
+
     fn run_command(
         cmd: &str,
         path: &str,
@@ -99,10 +209,44 @@ impl ProcessFilter {
         if program.is_empty() {
             return Ok(input.to_vec());
         }
+        Self::run_program(&program, &args, workdir, input)
+    }
+
+    /// Run `program` with `args`, feeding it `input` and returning its stdout.
+    fn run_program(
+        program: &str,
+        args: &[String],
+        workdir: Option<&Path>,
+        input: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let start = std::time::Instant::now();
+        let result = Self::run_program_output(program, args, workdir, input)
+            .and_then(|output| Self::check_output(program, output));
 
-        let mut command = Command::new(&program);
+        trace::event(|| match &result {
+            Ok(output) => format!(
+                "'{}' wrote {} bytes, read {} bytes in {:?}",
+                program,
+                input.len(),
+                output.len(),
+                start.elapsed()
+            ),
+            Err(e) => format!("'{}' failed after {:?}: {}", program, start.elapsed(), e),
+        });
+        result
+    }
+
+    /// Run `program` like [`run_program`](Self::run_program), but return how
+    /// it exited instead of failing when it exits unsuccessfully.
+    fn run_program_output(
+        program: &str,
+        args: &[String],
+        workdir: Option<&Path>,
+        input: &[u8],
+    ) -> Result<Output, Error> {
+        let mut command = Command::new(program);
         command
-            .args(&args)
+            .args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
@@ -112,17 +256,43 @@ impl ProcessFilter {
             command.current_dir(dir);
         }
 
-        let mut child = command
-            .spawn()
-            .map_err(|e| Error::from_str(&format!("failed to spawn '{}': {}", program, e)))?;
+        let mut child = command.spawn().map_err(|e| {
+            stats::note_failure(FailureKind::Spawn);
+            Error::from_str(&format!("failed to spawn '{}': {}", program, e))
+        })?;
+        stats::note_spawn();
+        trace::event(|| format!("spawned {:?} {:?} (pid {})", program, args, child.id()));
 
         // For large inputs, use streaming to avoid loading everything in memory
         let use_streaming = input.len() > STREAM_THRESHOLD;
 
         if use_streaming {
-            Self::run_streaming(&program, &mut child, input)
+            Self::run_streaming(program, &mut child, input)
         } else {
-            Self::run_buffered(&program, &mut child, input)
+            Self::run_buffered(program, &mut child, input)
+        }
+    }
+
+    /// The stdout of a command that exited successfully, or its stderr as
+    /// the error. Stderr of a successful command is logged as a warning.
+    fn check_output(program: &str, output: Output) -> Result<Vec<u8>, Error> {
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            if !output.stderr.is_empty() {
+                eprintln!(
+                    "[git2-process-filter] {} warning: {}",
+                    program,
+                    stderr_str.trim()
+                );
+            }
+            Ok(output.stdout)
+        } else {
+            stats::note_failure(FailureKind::ExitStatus);
+            Err(Error::from_str(&format!(
+                "'{}' failed: {}",
+                program,
+                stderr_str.trim()
+            )))
         }
     }
 
@@ -131,7 +301,7 @@ impl ProcessFilter {
         program: &str,
         child: &mut std::process::Child,
         input: &[u8],
-    ) -> Result<Vec<u8>, Error> {
+    ) -> Result<Output, Error> {
         // Write input to stdin
         if let Some(mut stdin) = child.stdin.take() {
             stdin
@@ -157,32 +327,17 @@ impl ProcessFilter {
         loop {
             match child.try_wait() {
                 Ok(Some(status)) => {
-                    // Log stderr as warning if present (even on success)
-                    if !stderr_data.is_empty() {
-                        let stderr_str = String::from_utf8_lossy(&stderr_data);
-                        if status.success() {
-                            eprintln!(
-                                "[git2-process-filter] {} warning: {}",
-                                program,
-                                stderr_str.trim()
-                            );
-                        }
-                    }
-
-                    if status.success() {
-                        return Ok(stdout_data);
-                    } else {
-                        let stderr_str = String::from_utf8_lossy(&stderr_data);
-                        return Err(Error::from_str(&format!(
-                            "'{}' failed: {}",
-                            program,
-                            stderr_str.trim()
-                        )));
-                    }
+                    trace::event(|| format!("'{}' exited with {}", program, status));
+                    return Ok(Output {
+                        status,
+                        stdout: stdout_data,
+                        stderr: stderr_data,
+                    });
                 }
                 Ok(None) => {
                     if start.elapsed() > DEFAULT_TIMEOUT {
                         let _ = child.kill();
+                        stats::note_failure(FailureKind::Timeout);
                         return Err(Error::from_str(&format!(
                             "'{}' timed out after {:?}",
                             program, DEFAULT_TIMEOUT
@@ -205,7 +360,7 @@ impl ProcessFilter {
         program: &str,
         child: &mut std::process::Child,
         input: &[u8],
-    ) -> Result<Vec<u8>, Error> {
+    ) -> Result<Output, Error> {
         use std::thread;
 
         let mut stdin = child
@@ -248,41 +403,575 @@ impl ProcessFilter {
         let status = child
             .wait()
             .map_err(|e| Error::from_str(&format!("failed to wait for '{}': {}", program, e)))?;
+        trace::event(|| format!("'{}' exited with {}", program, status));
 
-        if status.success() {
-            // Log stderr as warning if present
-            if !stderr_output.is_empty() {
-                let stderr_str = String::from_utf8_lossy(&stderr_output);
-                eprintln!(
-                    "[git2-process-filter] {} warning: {}",
-                    program,
-                    stderr_str.trim()
+        Ok(Output {
+            status,
+            stdout: output,
+            stderr: stderr_output,
+        })
+    }
+}
+
+impl ProcessFilter {
+    /// A filter named `name` run by `driver`, with every option off.
+    fn new(name: &str, driver: Driver) -> Self {
+        ProcessFilter {
+            name: name.to_string(),
+            driver,
+            required: false,
+            verify_pointers: None,
+            smudge_cache: None,
+            clean_cache: None,
+            verify_roundtrip: false,
+            resolve_pointers: None,
+            stats: Default::default(),
+            observers: Default::default(),
+        }
+    }
+
+    /// Build the filter configured by `filter.<name>.*`.
+    fn from_config(repo: &git2::Repository, name: &str) -> Result<Self, Error> {
+        let config = repo.config()?;
+
+        let clean_key = format!("filter.{}.clean", name);
+        let smudge_key = format!("filter.{}.smudge", name);
+        let process_key = format!("filter.{}.process", name);
+        let required_key = format!("filter.{}.required", name);
+        let builtin_key = format!("filter.{}.builtin", name);
+        let missing_key = format!("filter.{}.missing", name);
+        let verify_key = format!("filter.{}.verifypointer", name);
+        let cache_key = format!("filter.{}.smudgecache", name);
+        let cache_size_key = format!("filter.{}.smudgecachesize", name);
+        let clean_cache_key = format!("filter.{}.cleancache", name);
+        let roundtrip_key = format!("filter.{}.verifyroundtrip", name);
+
+        let clean_cmd = config.get_string(&clean_key).unwrap_or_default();
+        let smudge_cmd = config.get_string(&smudge_key).unwrap_or_default();
+        let process_cmd = config.get_string(&process_key).unwrap_or_default();
+        let required = config.get_bool(&required_key).unwrap_or(false);
+        let builtin = config.get_string(&builtin_key).unwrap_or_default();
+        let verify_pointers = match config.get_bool(&verify_key) {
+            Ok(true) => Some(lfs::LfsStore::open(repo)?),
+            _ => None,
+        };
+
+        let missing_policy = || match config.get_string(&missing_key) {
+            Ok(missing) => lfs::MissingObject::from_config(repo, &missing)
+                .map(Some)
+                .map_err(|e| Error::from_str(&format!("invalid {}: {}", missing_key, e.message()))),
+            Err(_) => Ok(None),
+        };
+        let resolve_pointers = match missing_policy()? {
+            Some(policy) if builtin.is_empty() && !process_cmd.is_empty() => {
+                Some(lfs::LfsFilter::open(repo)?.missing_objects(policy))
+            }
+            _ => None,
+        };
+
+        let driver = match builtin.as_str() {
+            "" if process_cmd.is_empty() => Driver::Commands {
+                clean: clean_cmd,
+                smudge: smudge_cmd,
+            },
+            "" => Driver::Process {
+                process: Arc::new(ProcessDriver::new(&process_cmd, repo.workdir())),
+                clean: clean_cmd,
+                smudge: smudge_cmd,
+            },
+            "lfs" => {
+                let mut filter = lfs::LfsFilter::open(repo)?;
+                if let Some(policy) = missing_policy()? {
+                    filter = filter.missing_objects(policy);
+                }
+                Driver::InProcess(Box::new(filter))
+            }
+            "git-crypt" => Driver::InProcess(Box::new(git_crypt::GitCryptFilter::open(repo)?)),
+            other => {
+                return Err(Error::from_str(&format!(
+                    "unknown {} '{}'",
+                    builtin_key, other
+                )))
+            }
+        };
+
+        // Caches are keyed by what produces the output
+        let identity = |mode: FilterMode| match &driver {
+            Driver::Commands { clean, .. } if mode == FilterMode::ToOdb => clean.clone(),
+            Driver::Commands { smudge, .. } => smudge.clone(),
+            Driver::Process { clean, .. } if mode == FilterMode::ToOdb => {
+                format!("process {}\0{}", process_cmd, clean)
+            }
+            Driver::Process { smudge, .. } => format!("process {}\0{}", process_cmd, smudge),
+            _ => format!("builtin {}", builtin),
+        };
+        let smudge_cache = match config.get_path(&cache_key) {
+            Ok(dir) if !dir.as_os_str().is_empty() => {
+                let max_size = config
+                    .get_i64(&cache_size_key)
+                    .map_or(smudge_cache::DEFAULT_MAX_SIZE, |size| size.max(0) as u64);
+                let cache = smudge_cache::SmudgeCache::open(
+                    &repo.commondir().join(dir),
+                    max_size,
+                    name,
+                    &identity(FilterMode::ToWorktree),
                 );
+                // Output may depend on the path if the driver is told it
+                let sees_path = match &driver {
+                    Driver::Commands { smudge, .. } => smudge.contains("%f"),
+                    Driver::Process { .. } => true,
+                    _ => false,
+                };
+                Some(if sees_path {
+                    cache.keyed_by_path()
+                } else {
+                    cache
+                })
             }
-            Ok(output)
-        } else {
-            let stderr_str = String::from_utf8_lossy(&stderr_output);
-            Err(Error::from_str(&format!(
-                "'{}' failed: {}",
-                program,
-                stderr_str.trim()
-            )))
+            _ => None,
+        };
+        // Stat data is per worktree, so the clean cache is too
+        let clean_cache = match config.get_bool(&clean_cache_key) {
+            Ok(true) => Some(clean_cache::CleanCache::open(
+                &repo.path().join("clean-cache"),
+                name,
+                &identity(FilterMode::ToOdb),
+            )),
+            _ => None,
+        };
+        Ok(ProcessFilter {
+            // Builtins guard content (missing LFS objects, git-crypt keys):
+            // their errors always fail
+            required: required || !builtin.is_empty(),
+            verify_pointers,
+            smudge_cache,
+            clean_cache,
+            verify_roundtrip: config.get_bool(&roundtrip_key).unwrap_or(false),
+            resolve_pointers,
+            ..ProcessFilter::new(name, driver)
+        })
+    }
+
+    /// The single-shot command this filter runs for `mode`, if it runs one.
+    fn command(&self, mode: FilterMode) -> Option<&str> {
+        let cmd = match (&self.driver, mode) {
+            (Driver::Commands { clean, .. }, FilterMode::ToOdb) => clean,
+            (Driver::Commands { smudge, .. }, FilterMode::ToWorktree) => smudge,
+            _ => return None,
+        };
+        (!cmd.trim().is_empty()).then_some(cmd.as_str())
+    }
+
+    /// Whether this filter has a clean side; without one, clean passes
+    /// content through.
+    fn cleans(&self) -> bool {
+        match &self.driver {
+            Driver::Commands { .. } => self.command(FilterMode::ToOdb).is_some(),
+            Driver::Process { process, clean, .. } => {
+                !clean.trim().is_empty()
+                    || process
+                        .capabilities()
+                        .is_ok_and(|capabilities| capabilities.clean())
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether this filter has a smudge side; without one, smudge passes
+    /// content through.
+    fn smudges(&self) -> bool {
+        match &self.driver {
+            Driver::Commands { .. } => self.command(FilterMode::ToWorktree).is_some(),
+            Driver::Process {
+                process, smudge, ..
+            } => {
+                !smudge.trim().is_empty()
+                    || process
+                        .capabilities()
+                        .is_ok_and(|capabilities| capabilities.smudge())
+            }
+            _ => true,
+        }
+    }
+
+    /// What runs this filter for `mode`, and its command if it has one.
+    fn describe(&self, mode: FilterMode) -> (DriverKind, Option<&str>) {
+        match &self.driver {
+            Driver::Commands { .. } => (DriverKind::Commands, self.command(mode)),
+            Driver::Process { process, .. } => (DriverKind::Process, Some(process.command())),
+            Driver::InProcess(_) => (DriverKind::InProcess, None),
+            Driver::Chain(_) => (DriverKind::Chain, None),
+        }
+    }
+
+    /// Long-running processes used by this filter, including chain stages.
+    fn processes(&self) -> Vec<Arc<ProcessDriver>> {
+        match &self.driver {
+            Driver::Process { process, .. } => vec![Arc::clone(process)],
+            Driver::Chain(stages) => stages.iter().flat_map(|s| s.processes()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Run the driver for one blob.
+    fn filter(
+        &self,
+        mode: FilterMode,
+        path: &str,
+        workdir: Option<&Path>,
+        input: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        match &self.driver {
+            Driver::Commands { clean, smudge } => {
+                let cmd = match mode {
+                    FilterMode::ToOdb => clean,
+                    FilterMode::ToWorktree => smudge,
+                };
+                Self::run_command(cmd, path, workdir, input)
+            }
+            Driver::Process {
+                process,
+                clean,
+                smudge,
+            } => {
+                let (capability, fallback) = match mode {
+                    FilterMode::ToOdb => ("clean", clean),
+                    FilterMode::ToWorktree => ("smudge", smudge),
+                };
+                match process.apply(capability, path, workdir, input)? {
+                    Outcome::Filtered(output) => match &self.resolve_pointers {
+                        Some(lfs) if mode == FilterMode::ToWorktree => lfs.smudge(path, &output),
+                        _ => Ok(output),
+                    },
+                    Outcome::Unavailable(reason) if !fallback.trim().is_empty() => {
+                        trace::event(|| format!("{}; running '{}' instead", reason, fallback));
+                        Self::run_command(fallback, path, workdir, input)
+                    }
+                    Outcome::Declined(reason) | Outcome::Unavailable(reason) => {
+                        self.decline(&reason, input)
+                    }
+                }
+            }
+            Driver::InProcess(filter) => {
+                let start = std::time::Instant::now();
+                let result = filter
+                    .apply(mode, path, input)
+                    .inspect_err(|_| stats::note_failure(FailureKind::Filter));
+                trace::event(|| match &result {
+                    Ok(output) => format!(
+                        "in-process '{}' {:?} '{}': {} bytes in, {} bytes out in {:?}",
+                        self.name,
+                        mode,
+                        path,
+                        input.len(),
+                        output.len(),
+                        start.elapsed()
+                    ),
+                    Err(e) => format!(
+                        "in-process '{}' {:?} '{}' failed: {}",
+                        self.name, mode, path, e
+                    ),
+                });
+                result.or_else(|e| self.decline(e.message(), input))
+            }
+            Driver::Chain(stages) => chain::run(&self.name, stages, mode, path, workdir, input),
         }
     }
 }
 
 impl Filter for ProcessFilter {
     fn apply(&self, src: &FilterSource<'_>, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let start = std::time::Instant::now();
+        stats::Recorder::begin();
+        let observers = self
+            .observers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let (driver, command) = self.describe(src.mode());
+        let mut request = FilterRequest {
+            filter: &self.name,
+            path: src.path().unwrap_or(""),
+            mode: src.mode(),
+            driver,
+            command,
+            input: Cow::Borrowed(input),
+        };
+
+        let vetoed = observers
+            .iter()
+            .try_for_each(|observer| observer.before(&mut request))
+            .inspect_err(|_| stats::note_failure(FailureKind::Vetoed));
+        let filter_start = std::time::Instant::now();
+        let result = vetoed.and_then(|()| self.apply_uncounted(src, request.input()));
+        let duration = filter_start.elapsed();
+        self.stats.record(
+            &self.name,
+            src.mode(),
+            request.input().len(),
+            result.as_ref().ok().map(Vec::len),
+            start.elapsed(),
+        );
+
+        let event = FilterEvent {
+            filter: &self.name,
+            path: request.path,
+            mode: request.mode,
+            driver,
+            command,
+            input_size: request.input().len(),
+            output_size: result.as_ref().ok().map(Vec::len),
+            duration,
+            result: result.as_ref().map(|_| ()),
+        };
+        for observer in &observers {
+            observer.after(&event);
+        }
+        result
+    }
+}
+
+impl ProcessFilter {
+    /// [`Filter::apply`] without the statistics.
+    fn apply_uncounted(&self, src: &FilterSource<'_>, input: &[u8]) -> Result<Vec<u8>, Error> {
         let path = src.path().unwrap_or("");
         let workdir = src.workdir();
-        match src.mode() {
-            FilterMode::ToOdb => {
-                Self::run_command(&self.clean_cmd, path, workdir.as_deref(), input)
+        let smudge_cache = self
+            .smudge_cache
+            .as_ref()
+            .filter(|_| src.mode() == FilterMode::ToWorktree);
+        if let Some(output) = smudge_cache.and_then(|cache| cache.get(path, input)) {
+            stats::note_cache_hit();
+            return Ok(output);
+        }
+        let clean_cache = self
+            .clean_cache
+            .as_ref()
+            .filter(|_| src.mode() == FilterMode::ToOdb)
+            .zip(workdir.as_deref());
+        if let Some(output) =
+            clean_cache.and_then(|(cache, workdir)| cache.get(workdir, path, input))
+        {
+            stats::note_cache_hit();
+            return Ok(output);
+        }
+
+        let output = self.filter(src.mode(), path, workdir.as_deref(), input)?;
+        if let (FilterMode::ToOdb, Some(store)) = (src.mode(), &self.verify_pointers) {
+            lfs::verify_clean_output(store, &self.name, path, &output)
+                .inspect_err(|_| stats::note_failure(FailureKind::Verification))?;
+        }
+        if src.mode() == FilterMode::ToOdb && (self.verify_roundtrip || roundtrip::enabled_by_env())
+        {
+            roundtrip::verify(self, path, workdir.as_deref(), input, &output)
+                .inspect_err(|_| stats::note_failure(FailureKind::Verification))?;
+        }
+        // Content passed through (e.g. declined by the process, or after a
+        // failure of an optional filter) is not cached
+        if output != input {
+            if let Some(cache) = smudge_cache {
+                cache.put(path, input, &output);
             }
-            FilterMode::ToWorktree => {
-                Self::run_command(&self.smudge_cmd, path, workdir.as_deref(), input)
+            if let Some((cache, workdir)) = clean_cache {
+                cache.put(workdir, path, input, &output);
             }
         }
+        Ok(output)
+    }
+}
+
+/// Handle for a filter registered by this crate.
+///
+/// The filter remains active until this handle is dropped. Dropping it also
+/// stops the filter's long-running process (if any): its stdin is closed and
+/// it is killed if it has not exited after a short grace period.
+pub struct ProcessFilterRegistration {
+    _registration: FilterRegistration,
+    /// Long-running processes, in stage order for a [`FilterChain`].
+    processes: Vec<Arc<ProcessDriver>>,
+    stats: Arc<stats::Recorder>,
+    observers: observer::Observers,
+    /// Set by [`bypass_clean_cache`](Self::bypass_clean_cache), if the filter
+    /// has a clean cache.
+    clean_cache_bypass: Option<Arc<AtomicBool>>,
+}
+
+impl ProcessFilterRegistration {
+    fn register(filter: ProcessFilter) -> Result<Self, Error> {
+        let attributes = format!("filter={}", filter.name);
+        Self::register_with(filter, &attributes, filter_priority::DRIVER)
+    }
+
+    /// Register `filter` for paths with `attributes`, at `priority`.
+    fn register_with(
+        filter: ProcessFilter,
+        attributes: &str,
+        priority: i32,
+    ) -> Result<Self, Error> {
+        let processes = filter.processes();
+        let stats = Arc::clone(&filter.stats);
+        let observers = Arc::clone(&filter.observers);
+        let clean_cache_bypass = filter.clean_cache.as_ref().map(|c| c.bypass_flag());
+        let name = filter.name.clone();
+        let registration = filter_register(&name, attributes, priority, filter)?;
+        Ok(ProcessFilterRegistration {
+            _registration: registration,
+            processes,
+            stats,
+            observers,
+            clean_cache_bypass,
+        })
+    }
+
+    /// Set the metadata sent with each request to the long-running filter
+    /// process, e.g. the ref and commit being checked out.
+    ///
+    /// Applies to subsequent requests until replaced. Has no effect on filters
+    /// that use single-shot `clean`/`smudge` commands. For a [`FilterChain`],
+    /// it applies to every stage that uses a long-running process.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use git2::Repository;
+    /// use git2_process_filter::{register_process_filter, RequestMetadata};
+    ///
+    /// let repo = Repository::open(".")?;
+    /// let reg = register_process_filter(&repo, "lfs")?;
+    ///
+    /// let head = repo.head()?;
+    /// reg.set_metadata(RequestMetadata {
+    ///     ref_name: head.name().map(str::to_string),
+    ///     treeish: head.target(),
+    ///     ..Default::default()
+    /// })?;
+    /// repo.checkout_head(None)?;
+    /// # Ok::<(), git2::Error>(())
+    /// ```
+    pub fn set_metadata(&self, metadata: RequestMetadata) -> Result<(), Error> {
+        metadata.validate()?;
+        for process in &self.processes {
+            process.set_metadata(metadata.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Capabilities negotiated with the filter's long-running process, or `None`
+    /// if the filter uses single-shot `clean`/`smudge` commands. For a
+    /// [`FilterChain`], this is the first stage that uses a long-running process.
+    ///
+    /// Starts the process (and performs the handshake) if it is not running
+    /// yet. Requests for a direction the process does not advertise never
+    /// reach it: they pass through, or fail if `filter.<name>.required` is set.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use git2::Repository;
+    /// use git2_process_filter::register_process_filter;
+    ///
+    /// let repo = Repository::open(".")?;
+    /// let reg = register_process_filter(&repo, "lfs")?;
+    ///
+    /// if let Some(caps) = reg.process_capabilities()? {
+    ///     if !caps.delay() {
+    ///         eprintln!("warning: git-lfs is too old to support delayed checkout");
+    ///     }
+    /// }
+    /// # Ok::<(), git2::Error>(())
+    /// ```
+    pub fn process_capabilities(&self) -> Result<Option<ProcessCapabilities>, Error> {
+        self.processes.first().map(|p| p.capabilities()).transpose()
+    }
+
+    /// Statistics of the filter's invocations since it was registered:
+    /// invocations, bytes and time per direction, failures by kind, and
+    /// process spawns vs reuses. A [`FilterChain`] is counted as one filter.
+    ///
+    /// With the `metrics` feature, every invocation is also reported to the
+    /// [`metrics`](https://docs.rs/metrics) recorder as
+    /// `git2_process_filter_*` counters and a
+    /// `git2_process_filter_duration_seconds` histogram, labelled with the
+    /// filter name and direction.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use git2::Repository;
+    /// use git2_process_filter::register_process_filter;
+    ///
+    /// let repo = Repository::open(".")?;
+    /// let reg = register_process_filter(&repo, "lfs")?;
+    /// repo.checkout_head(None)?;
+    ///
+    /// let stats = reg.stats();
+    /// eprintln!(
+    ///     "{} smudges in {:?}, {} processes started, {} timeouts",
+    ///     stats.smudge.invocations,
+    ///     stats.smudge.wall_time,
+    ///     stats.spawns,
+    ///     stats.timeouts()
+    /// );
+    /// # Ok::<(), git2::Error>(())
+    /// ```
+    pub fn stats(&self) -> FilterStats {
+        self.stats.snapshot()
+    }
+
+    /// Run `observer` around every later invocation of the filter: it sees
+    /// each blob before it is filtered, and may rewrite or veto it, and the
+    /// outcome afterwards. See [`FilterObserver`].
+    pub fn add_observer(&self, observer: impl FilterObserver) {
+        self.observers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::new(observer));
+    }
+
+    /// Bypass `filter.<name>.cleancache` for later cleans, or stop bypassing
+    /// it: while bypassed, every file is cleaned again and its entry
+    /// refreshed. Has no effect if the cache is not enabled.
+    ///
+    /// `GIT2_PROCESS_FILTER_NO_CLEAN_CACHE=1` bypasses the cache whatever
+    /// this is set to.
+    pub fn bypass_clean_cache(&self, bypass: bool) {
+        if let Some(flag) = &self.clean_cache_bypass {
+            flag.store(bypass, Ordering::Relaxed);
+        }
+    }
+
+    /// Unregister the filter and stop its long-running process, reporting how
+    /// the session ended.
+    ///
+    /// Filters without a long-running process return an empty report. For a
+    /// [`FilterChain`], the reports of its processes are combined.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use git2::Repository;
+    /// use git2_process_filter::register_process_filter;
+    ///
+    /// let repo = Repository::open(".")?;
+    /// let reg = register_process_filter(&repo, "lfs")?;
+    ///
+    /// // ... checkout, add, etc.
+    ///
+    /// let report = reg.shutdown();
+    /// assert!(report.success(), "{}", String::from_utf8_lossy(&report.stderr));
+    /// # Ok::<(), git2::Error>(())
+    /// ```
+    pub fn shutdown(self) -> ShutdownReport {
+        let ProcessFilterRegistration {
+            _registration,
+            processes,
+            ..
+        } = self;
+        drop(_registration);
+        processes
+            .iter()
+            .map(|p| p.shutdown())
+            .fold(ShutdownReport::default(), ShutdownReport::merge)
     }
 }
 
@@ -291,6 +980,41 @@ impl Filter for ProcessFilter {
 /// Reads `filter.<name>.clean` and `filter.<name>.smudge` from the repository's
 /// config and registers a filter that executes those commands.
 ///
+/// If `filter.<name>.process` is set, it takes precedence: the command is
+/// started once as a long-running filter process and reused for every blob.
+/// A process that crashes is restarted (a limited number of times) and the
+/// affected blob is retried once. When the process cannot filter a blob, the
+/// content is passed through unchanged unless `filter.<name>.required` is true.
+///
+/// `clean`/`smudge` are still used for a direction the process does not
+/// advertise, and for every blob if the process cannot be started.
+///
+/// `filter.<name>.builtin = lfs` replaces all of these with the built-in Git
+/// LFS filter, for machines without the `git-lfs` binary. The git CLI ignores
+/// this key and keeps using the commands. `filter.<name>.missing` (`keep`,
+/// `fetch` from the remote's LFS server, or `fail`, the default) decides what
+/// smudge does with objects that are not in the local store; see [`lfs`].
+/// With `filter.<name>.process`, `missing` applies to LFS pointers the
+/// process smudges to: they are replaced with the object from the local store,
+/// or as `missing` says.
+/// `filter.<name>.builtin = git-crypt` likewise replaces `git-crypt` with
+/// [`git_crypt`], using the key of the unlocked repository.
+///
+/// `filter.<name>.verifypointer = true` rejects clean output that is not an
+/// LFS pointer to an object in the local store, whatever the driver.
+///
+/// `filter.<name>.smudgecache = <dir>` caches smudge output by content (and
+/// path, for `%f` commands and long-running processes), so smudging the same
+/// blob again does not run the driver; the cache is
+/// limited to `filter.<name>.smudgecachesize` bytes (default 1 GiB).
+/// `filter.<name>.cleancache = true` caches clean output in `.git/clean-cache`
+/// for files whose stat data has not changed, unless
+/// `GIT2_PROCESS_FILTER_NO_CLEAN_CACHE` is set.
+///
+/// `filter.<name>.verifyroundtrip = true`, or `GIT2_PROCESS_FILTER_VERIFY_ROUNDTRIP`
+/// for every filter, rejects clean output that does not smudge back to the
+/// original content or that cleaning again changes, which is useful in CI.
+///
 /// # Arguments
 ///
 /// * `repo` - The repository to read config from
@@ -298,7 +1022,7 @@ impl Filter for ProcessFilter {
 ///
 /// # Returns
 ///
-/// A [`FilterRegistration`] handle. The filter remains active until this handle is dropped.
+/// A [`ProcessFilterRegistration`] handle. The filter remains active until this handle is dropped.
 ///
 /// # Example
 ///
@@ -319,22 +1043,9 @@ impl Filter for ProcessFilter {
 pub fn register_process_filter(
     repo: &git2::Repository,
     name: &str,
-) -> Result<FilterRegistration, Error> {
-    let config = repo.config()?;
-
-    let clean_key = format!("filter.{}.clean", name);
-    let smudge_key = format!("filter.{}.smudge", name);
-
-    let clean_cmd = config.get_string(&clean_key).unwrap_or_default();
-    let smudge_cmd = config.get_string(&smudge_key).unwrap_or_default();
-
-    let filter = ProcessFilter {
-        clean_cmd,
-        smudge_cmd,
-    };
-
-    let attributes = format!("filter={}", name);
-    filter_register(name, &attributes, filter_priority::DRIVER, filter)
+) -> Result<ProcessFilterRegistration, Error> {
+    let filter = ProcessFilter::from_config(repo, name)?;
+    ProcessFilterRegistration::register(filter)
 }
 
 /// Register a filter with explicit clean and smudge commands.
@@ -350,7 +1061,7 @@ pub fn register_process_filter(
 ///
 /// # Returns
 ///
-/// A [`FilterRegistration`] handle. The filter remains active until this handle is dropped.
+/// A [`ProcessFilterRegistration`] handle. The filter remains active until this handle is dropped.
 ///
 /// # Example
 ///
@@ -371,14 +1082,84 @@ pub fn register_process_filter_with_commands(
     name: &str,
     clean_cmd: &str,
     smudge_cmd: &str,
-) -> Result<FilterRegistration, Error> {
+) -> Result<ProcessFilterRegistration, Error> {
+    let filter = ProcessFilter::new(
+        name,
+        Driver::Commands {
+            clean: clean_cmd.to_string(),
+            smudge: smudge_cmd.to_string(),
+        },
+    );
+
+    ProcessFilterRegistration::register(filter)
+}
+
+/// Register a filter implemented in Rust.
+///
+/// The filter runs inside this process but is otherwise handled exactly like
+/// one backed by external commands, so the same filter name can be served by
+/// either. See [`InProcessFilter`] for an example.
+///
+/// # Arguments
+///
+/// * `name` - The filter name (used in .gitattributes as `filter=<name>`)
+/// * `required` - Whether an error fails the operation, like
+///   `filter.<name>.required`; otherwise the content passes through unfiltered
+/// * `filter` - The Rust filter, or a closure taking `(FilterMode, &str, &[u8])`
+///
+/// # Returns
+///
+/// A [`ProcessFilterRegistration`] handle. The filter remains active until this handle is dropped.
+pub fn register_in_process_filter<F: InProcessFilter>(
+    name: &str,
+    required: bool,
+    filter: F,
+) -> Result<ProcessFilterRegistration, Error> {
     let filter = ProcessFilter {
-        clean_cmd: clean_cmd.to_string(),
-        smudge_cmd: smudge_cmd.to_string(),
+        required,
+        ..ProcessFilter::new(name, Driver::InProcess(Box::new(filter)))
     };
+    ProcessFilterRegistration::register(filter)
+}
 
-    let attributes = format!("filter={}", name);
-    filter_register(name, &attributes, filter_priority::DRIVER, filter)
+/// Honor the `working-tree-encoding` attribute, which libgit2 ignores.
+///
+/// Files with `working-tree-encoding=<encoding>` in `.gitattributes` are
+/// converted to UTF-8 when added and back to `<encoding>` when checked out,
+/// with git's byte order mark checks and `core.checkRoundtripEncoding`. As in
+/// git, the conversion runs after a `filter=` driver's clean and before its
+/// smudge.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::Repository;
+/// use git2_process_filter::register_working_tree_encoding;
+///
+/// let repo = Repository::open(".")?;
+///
+/// // .gitattributes: *.rc working-tree-encoding=UTF-16LE-BOM
+/// let _reg = register_working_tree_encoding(&repo)?;
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub fn register_working_tree_encoding(
+    repo: &git2::Repository,
+) -> Result<ProcessFilterRegistration, Error> {
+    let filter = ProcessFilter {
+        // Like git, content that cannot be converted fails a clean; smudge
+        // passes it through itself
+        required: true,
+        ..ProcessFilter::new(
+            encoding::ATTRIBUTE,
+            Driver::InProcess(Box::new(encoding::WorkingTreeEncoding::new(repo)?)),
+        )
+    };
+    // Between the CRLF and ident filters and the drivers, like git
+    ProcessFilterRegistration::register_with(
+        filter,
+        encoding::ATTRIBUTE,
+        filter_priority::DRIVER - 50,
+    )
 }
 
 #[cfg(test)]
@@ -436,6 +1217,15 @@ mod tests {
         assert!(args.is_empty());
     }
 
+    #[test]
+    fn test_expand_placeholders() {
+        let values = [('A', "/tmp/ours"), ('P', "dir/a b.lock")];
+        let expand = |arg| ProcessFilter::expand_placeholders(arg, &values);
+        assert_eq!(expand("--ours=%A"), "--ours=/tmp/ours");
+        assert_eq!(expand("%P"), "dir/a b.lock");
+        assert_eq!(expand("100%% %X %"), "100% %X %");
+    }
+
     #[test]
     fn test_run_command_cat() {
         let input = b"hello world";
@@ -494,4 +1284,25 @@ mod tests {
         let result = register_process_filter_with_commands("testcmd", "cat", "cat");
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_in_process_filter() {
+        let filter = ProcessFilter {
+            required: true,
+            ..ProcessFilter::new(
+                "upper",
+                Driver::InProcess(Box::new(
+                    |mode: FilterMode, _path: &str, input: &[u8]| match mode {
+                        FilterMode::ToOdb => Ok(input.to_ascii_uppercase()),
+                        FilterMode::ToWorktree => Err(Error::from_str("no smudge")),
+                    },
+                )),
+            )
+        };
+        let output = filter.filter(FilterMode::ToOdb, "a.txt", None, b"hello");
+        assert_eq!(output.unwrap(), b"HELLO");
+        assert!(filter
+            .filter(FilterMode::ToWorktree, "a.txt", None, b"HELLO")
+            .is_err());
+    }
 }
@@ -0,0 +1,208 @@
+//! `merge.<driver>.driver` support for merges made with git2.
+
+use crate::{trace, ProcessFilter};
+use git2::{AttrCheckFlags, AttrValue, Error, IndexConflict, Repository};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// `conflict-marker-size` when the attribute is not set.
+const DEFAULT_MARKER_SIZE: usize = 7;
+
+/// Drivers git implements itself; `merge=<name>` never runs a command for them.
+const BUILTIN_DRIVERS: &[&str] = &["text", "binary", "union"];
+
+/// Result of a [`MergeDriver`] run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    /// The merged content the driver left in `%A`, with conflict markers if
+    /// it could not merge cleanly.
+    pub content: Vec<u8>,
+    /// Whether the driver exited with a non-zero status, reporting conflicts.
+    pub conflict: bool,
+}
+
+/// An external merge driver, configured as `merge.<driver>.driver` and
+/// selected with the `merge=<driver>` attribute, which git2 merges ignore.
+///
+/// The command is parsed like a filter command (quotes, no shell syntax) and
+/// its arguments expanded as git does: `%O`, `%A` and `%B` are temporary files
+/// holding the ancestor's, ours and theirs versions, `%L` is the
+/// `conflict-marker-size` and `%P` the path. The driver leaves the merge
+/// result in `%A` and exits with a non-zero status if there are conflicts.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::Repository;
+/// use git2_process_filter::MergeDriver;
+/// use std::path::Path;
+///
+/// let repo = Repository::open(".")?;
+/// // .gitattributes: yarn.lock merge=lockfile
+/// // config:         merge.lockfile.driver = yarn-merge-driver %O %A %B %P
+/// let path = Path::new("yarn.lock");
+/// if let Some(driver) = MergeDriver::for_path(&repo, path)? {
+///     let result = driver.merge(b"ancestor", b"ours", b"theirs")?;
+///     if result.conflict {
+///         eprintln!("{} has conflicts", path.display());
+///     }
+/// }
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct MergeDriver {
+    name: String,
+    command: String,
+    path: PathBuf,
+    marker_size: usize,
+    workdir: Option<PathBuf>,
+}
+
+impl MergeDriver {
+    /// The merge driver for `path`, or `None` if its `merge` attribute does
+    /// not name a driver with a `merge.<driver>.driver` command. Git's
+    /// built-in `text`, `binary` and `union` drivers are not run here.
+    pub fn for_path(repo: &Repository, path: &Path) -> Result<Option<Self>, Error> {
+        let flags = AttrCheckFlags::FILE_THEN_INDEX;
+        let AttrValue::String(name) = AttrValue::from_string(repo.get_attr(path, "merge", flags)?)
+        else {
+            return Ok(None);
+        };
+        if BUILTIN_DRIVERS.contains(&name) {
+            return Ok(None);
+        }
+        let command = match repo.config()?.get_string(&format!("merge.{}.driver", name)) {
+            Ok(command) if !command.trim().is_empty() => command,
+            _ => return Ok(None),
+        };
+
+        let marker_size = match repo.get_attr(path, "conflict-marker-size", flags)? {
+            Some(size) => size.parse().unwrap_or(DEFAULT_MARKER_SIZE),
+            None => DEFAULT_MARKER_SIZE,
+        };
+        Ok(Some(MergeDriver {
+            name: name.to_string(),
+            command,
+            path: path.to_path_buf(),
+            marker_size,
+            workdir: repo.workdir().map(Path::to_path_buf),
+        }))
+    }
+
+    /// Run the merge driver of a conflicted path, e.g. from
+    /// [`Index::conflicts`](git2::Index::conflicts) after
+    /// [`Repository::merge_commits`]. Returns `None` if the path has no merge
+    /// driver, or is not a content conflict (deleted on one side).
+    pub fn resolve_conflict(
+        repo: &Repository,
+        conflict: &IndexConflict,
+    ) -> Result<Option<MergeResult>, Error> {
+        let (Some(ours), Some(theirs)) = (&conflict.our, &conflict.their) else {
+            return Ok(None);
+        };
+        let path = String::from_utf8_lossy(&ours.path).into_owned();
+        let Some(driver) = Self::for_path(repo, Path::new(&path))? else {
+            return Ok(None);
+        };
+        let blob = |id| repo.find_blob(id).map(|blob| blob.content().to_vec());
+        let ancestor = match &conflict.ancestor {
+            Some(entry) => blob(entry.id)?,
+            None => Vec::new(),
+        };
+        driver
+            .merge(&ancestor, &blob(ours.id)?, &blob(theirs.id)?)
+            .map(Some)
+    }
+
+    /// The driver name from the `merge` attribute.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Merge `ours` and `theirs`, which both derive from `ancestor`.
+    ///
+    /// Fails if the driver cannot be run, is killed by a signal, or does not
+    /// leave a readable `%A`.
+    pub fn merge(&self, ancestor: &[u8], ours: &[u8], theirs: &[u8]) -> Result<MergeResult, Error> {
+        let files = [
+            temp_file("O", ancestor)?,
+            temp_file("A", ours)?,
+            temp_file("B", theirs)?,
+        ];
+        let [ancestor_path, ours_path, theirs_path] = files
+            .each_ref()
+            .map(|f| f.path().to_string_lossy().into_owned());
+        let marker_size = self.marker_size.to_string();
+        let path = self.path.to_string_lossy();
+        let values = [
+            ('O', ancestor_path.as_str()),
+            ('A', ours_path.as_str()),
+            ('B', theirs_path.as_str()),
+            ('L', marker_size.as_str()),
+            ('P', path.as_ref()),
+        ];
+
+        let (program, args) = ProcessFilter::parse_command(&self.command, "%f");
+        let program = ProcessFilter::expand_placeholders(&program, &values);
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| ProcessFilter::expand_placeholders(arg, &values))
+            .collect();
+        let output =
+            ProcessFilter::run_program_output(&program, &args, self.workdir.as_deref(), b"")
+                .map_err(|e| self.error(e.message()))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            eprintln!(
+                "[git2-process-filter] merge driver '{}' for '{}': {}",
+                self.name,
+                path,
+                stderr.trim()
+            );
+        }
+        if output.status.code().is_none() {
+            return Err(self.error(&format!("'{}' was killed ({})", program, output.status)));
+        }
+
+        let content = std::fs::read(files[1].path())
+            .map_err(|e| self.error(&format!("failed to read the result: {}", e)))?;
+        let conflict = !output.status.success();
+        trace::event(|| {
+            format!(
+                "merge driver '{}' merged '{}' ({} bytes, {})",
+                self.name,
+                path,
+                content.len(),
+                if conflict { "conflicts" } else { "clean" }
+            )
+        });
+        Ok(MergeResult { content, conflict })
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::from_str(&format!(
+            "merge driver '{}' failed for '{}': {}",
+            self.name,
+            self.path.display(),
+            message
+        ))
+    }
+}
+
+/// Write one version of the merged file to a private temporary file, created
+/// exclusively and removed on drop.
+fn temp_file(version: &str, content: &[u8]) -> Result<NamedTempFile, Error> {
+    let write_error = |e: std::io::Error| {
+        Error::from_str(&format!(
+            "failed to write %{} for the merge driver: {}",
+            version, e
+        ))
+    };
+    let mut file = tempfile::Builder::new()
+        .prefix("git2-merge-")
+        .suffix(&format!("-{}", version))
+        .tempfile()
+        .map_err(write_error)?;
+    file.write_all(content).map_err(write_error)?;
+    Ok(file)
+}
@@ -0,0 +1,164 @@
+//! Hooks run around each filter invocation, added with
+//! [`ProcessFilterRegistration::add_observer`](crate::ProcessFilterRegistration::add_observer).
+
+use git2::{Error, FilterMode};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Observers of one registered filter, shared with its registration handle.
+pub(crate) type Observers = Arc<RwLock<Vec<Arc<dyn FilterObserver>>>>;
+
+/// What runs a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DriverKind {
+    /// Single-shot `filter.<name>.clean`/`smudge` commands.
+    Commands,
+    /// A long-running `filter.<name>.process`.
+    Process,
+    /// Rust code, including the built-in `lfs` and `git-crypt` filters.
+    InProcess,
+    /// A [`FilterChain`](crate::FilterChain).
+    Chain,
+}
+
+impl DriverKind {
+    /// Short lowercase name, e.g. `in-process`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DriverKind::Commands => "commands",
+            DriverKind::Process => "process",
+            DriverKind::InProcess => "in-process",
+            DriverKind::Chain => "chain",
+        }
+    }
+}
+
+impl fmt::Display for DriverKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A blob about to be filtered, as seen by [`FilterObserver::before`].
+pub struct FilterRequest<'a> {
+    pub(crate) filter: &'a str,
+    pub(crate) path: &'a str,
+    pub(crate) mode: FilterMode,
+    pub(crate) driver: DriverKind,
+    pub(crate) command: Option<&'a str>,
+    pub(crate) input: Cow<'a, [u8]>,
+}
+
+impl FilterRequest<'_> {
+    /// Name of the registered filter.
+    pub fn filter(&self) -> &str {
+        self.filter
+    }
+
+    /// Path of the file, relative to the working tree.
+    pub fn path(&self) -> &str {
+        self.path
+    }
+
+    /// [`FilterMode::ToOdb`] for clean, [`FilterMode::ToWorktree`] for smudge.
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    /// What runs the filter.
+    pub fn driver(&self) -> DriverKind {
+        self.driver
+    }
+
+    /// The command that runs, for [`DriverKind::Commands`] and
+    /// [`DriverKind::Process`].
+    pub fn command(&self) -> Option<&str> {
+        self.command
+    }
+
+    /// The content to filter, as rewritten by earlier observers.
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+
+    /// Filter `input` instead of the original content.
+    pub fn set_input(&mut self, input: Vec<u8>) {
+        self.input = Cow::Owned(input);
+    }
+}
+
+/// A finished invocation, as seen by [`FilterObserver::after`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FilterEvent<'a> {
+    /// Name of the registered filter.
+    pub filter: &'a str,
+    /// Path of the file, relative to the working tree.
+    pub path: &'a str,
+    /// [`FilterMode::ToOdb`] for clean, [`FilterMode::ToWorktree`] for smudge.
+    pub mode: FilterMode,
+    /// What ran the filter.
+    pub driver: DriverKind,
+    /// The command that ran, for [`DriverKind::Commands`] and
+    /// [`DriverKind::Process`].
+    pub command: Option<&'a str>,
+    /// Size of the filtered content, after observers rewrote it.
+    pub input_size: usize,
+    /// Size of the output, if the invocation succeeded.
+    pub output_size: Option<usize>,
+    /// Time spent filtering, not counting the observers.
+    pub duration: Duration,
+    /// The error the invocation failed with, including a veto.
+    pub result: Result<(), &'a Error>,
+}
+
+/// Hooks run around every invocation of a registered filter, e.g. to audit
+/// which files went through it or to report progress.
+///
+/// Observers run in the order they were added, on the thread running the
+/// filter, so they should be quick. Both methods do nothing by default.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::{Error, FilterMode, Repository};
+/// use git2_process_filter::{register_process_filter, FilterEvent, FilterObserver, FilterRequest};
+///
+/// /// Logs every file and refuses to clean files that look like keys.
+/// struct Audit;
+///
+/// impl FilterObserver for Audit {
+///     fn before(&self, request: &mut FilterRequest<'_>) -> Result<(), Error> {
+///         if request.mode() == FilterMode::ToOdb && request.input().starts_with(b"-----BEGIN") {
+///             return Err(Error::from_str(&format!("refusing to add {}", request.path())));
+///         }
+///         Ok(())
+///     }
+///
+///     fn after(&self, event: &FilterEvent<'_>) {
+///         eprintln!("{} {:?} {} in {:?}", event.filter, event.mode, event.path, event.duration);
+///     }
+/// }
+///
+/// let repo = Repository::open(".")?;
+/// let reg = register_process_filter(&repo, "crypt")?;
+/// reg.add_observer(Audit);
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub trait FilterObserver: Send + Sync + 'static {
+    /// Called before the blob is filtered. It may rewrite the content with
+    /// [`FilterRequest::set_input`], or veto the request by returning an
+    /// error, which fails the blob without running the filter.
+    fn before(&self, request: &mut FilterRequest<'_>) -> Result<(), Error> {
+        let _ = request;
+        Ok(())
+    }
+
+    /// Called after the blob was filtered, failed, or was vetoed.
+    fn after(&self, event: &FilterEvent<'_>) {
+        let _ = event;
+    }
+}
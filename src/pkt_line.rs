@@ -0,0 +1,155 @@
+//! pkt-line framing used by git's long-running filter protocol.
+//!
+//! Each packet is a 4-digit hex length (which counts the 4 length bytes
+//! themselves) followed by the payload. The special packet `0000` is a flush
+//! packet and terminates a list of text lines or a stream of content.
+
+use std::io::{self, Read, Write};
+
+/// Maximum payload of a single packet (65520 bytes minus the 4-byte header).
+pub(crate) const MAX_PACKET_DATA: usize = 65516;
+
+/// Write a single data packet.
+pub(crate) fn write_packet<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() || data.len() > MAX_PACKET_DATA {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid packet size {}", data.len()),
+        ));
+    }
+    write!(w, "{:04x}", data.len() + 4)?;
+    w.write_all(data)
+}
+
+/// Write a flush packet.
+pub(crate) fn write_flush<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(b"0000")
+}
+
+/// Write text lines (each terminated by `\n`) followed by a flush packet.
+pub(crate) fn write_list<W: Write>(w: &mut W, lines: &[&str]) -> io::Result<()> {
+    for line in lines {
+        write_packet(w, format!("{}\n", line).as_bytes())?;
+    }
+    write_flush(w)
+}
+
+/// Write content split into maximum-size packets, followed by a flush packet.
+pub(crate) fn write_content<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(MAX_PACKET_DATA) {
+        write_packet(w, chunk)?;
+    }
+    write_flush(w)
+}
+
+/// Read a single packet, returning `None` for a flush packet.
+pub(crate) fn read_packet<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    r.read_exact(&mut header)?;
+    let len = std::str::from_utf8(&header)
+        .ok()
+        .and_then(|s| usize::from_str_radix(s, 16).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "invalid packet header {:?}",
+                    String::from_utf8_lossy(&header)
+                ),
+            )
+        })?;
+
+    match len {
+        0 => Ok(None),
+        1..=3 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid packet length {}", len),
+        )),
+        _ => {
+            let mut data = vec![0u8; len - 4];
+            r.read_exact(&mut data)?;
+            Ok(Some(data))
+        }
+    }
+}
+
+/// Read a text packet, stripping the trailing `\n`. Returns `None` for a flush packet.
+pub(crate) fn read_text<R: Read>(r: &mut R) -> io::Result<Option<String>> {
+    match read_packet(r)? {
+        Some(mut data) => {
+            if data.last() == Some(&b'\n') {
+                data.pop();
+            }
+            String::from_utf8(data)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Read text lines up to the next flush packet.
+pub(crate) fn read_list<R: Read>(r: &mut R) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    while let Some(line) = read_text(r)? {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Read content packets up to the next flush packet.
+pub(crate) fn read_content<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    while let Some(data) = read_packet(r)? {
+        content.extend_from_slice(&data);
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_list() {
+        let mut buf = Vec::new();
+        write_list(&mut buf, &["git-filter-client", "version=2"]).unwrap();
+        assert_eq!(buf, b"0016git-filter-client\n000eversion=2\n0000");
+    }
+
+    #[test]
+    fn test_list_roundtrip() {
+        let mut buf = Vec::new();
+        write_list(&mut buf, &["command=clean", "pathname=a b.txt"]).unwrap();
+        let lines = read_list(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(lines, vec!["command=clean", "pathname=a b.txt"]);
+    }
+
+    #[test]
+    fn test_content_roundtrip_multiple_packets() {
+        let data: Vec<u8> = (0..MAX_PACKET_DATA * 2 + 10)
+            .map(|i| (i % 256) as u8)
+            .collect();
+        let mut buf = Vec::new();
+        write_content(&mut buf, &data).unwrap();
+        assert_eq!(buf.len(), data.len() + 3 * 4 + 4);
+        assert_eq!(read_content(&mut Cursor::new(buf)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_empty_content_is_just_flush() {
+        let mut buf = Vec::new();
+        write_content(&mut buf, b"").unwrap();
+        assert_eq!(buf, b"0000");
+        assert!(read_content(&mut Cursor::new(buf)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_packet_invalid() {
+        assert!(read_packet(&mut Cursor::new(b"zzzz")).is_err());
+        assert!(read_packet(&mut Cursor::new(b"0002")).is_err());
+        // Truncated payload (process died mid-packet)
+        assert!(read_packet(&mut Cursor::new(b"0010abc")).is_err());
+    }
+}
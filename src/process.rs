@@ -0,0 +1,791 @@
+//! Long-running filter processes (`filter.<name>.process`).
+//!
+//! Implements the client side of git's long-running filter protocol: the
+//! process is spawned once, negotiates version and capabilities over pkt-line,
+//! and then serves every clean/smudge request until it exits. A process that
+//! dies or breaks the protocol is restarted, up to [`MAX_RESTARTS`] times.
+//!
+//! When the driver is dropped or shut down, the process is stopped the way git
+//! does it: its stdin is closed and it is given [`SHUTDOWN_GRACE`] to exit
+//! before being killed.
+
+use crate::pkt_line;
+use crate::stats::{self, FailureKind};
+use crate::trace::{self, Traced};
+use crate::ProcessFilter;
+use git2::{Error, ObjectType, Oid};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Maximum number of times a long-running process is restarted before the
+/// driver gives up on it.
+const MAX_RESTARTS: u32 = 3;
+
+/// How long a process may take to exit after its stdin is closed before it is killed.
+pub(crate) const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// The only protocol version git (and this crate) speaks.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Capabilities offered to the process during the handshake. `delay` is
+/// offered so callers can tell whether the process supports it, but
+/// `can-delay=1` is never sent, so the process always answers immediately.
+const OFFERED_CAPABILITIES: &[&str] =
+    &["capability=clean", "capability=smudge", "capability=delay"];
+
+/// Maximum amount of stderr kept for [`ShutdownReport::stderr`] (1MB).
+const STDERR_LIMIT: usize = 1024 * 1024;
+
+/// Reply to a single filter request.
+enum Reply {
+    Success(Vec<u8>),
+    /// `status=error`: this blob failed, later requests are unaffected.
+    Error,
+    /// `status=abort`: the process refuses all further requests for this command.
+    Abort,
+}
+
+/// Result of asking the long-running process to filter a blob.
+pub(crate) enum Outcome {
+    /// The process filtered the blob.
+    Filtered(Vec<u8>),
+    /// The process could not filter the blob. The caller decides between
+    /// passthrough and failure based on `filter.<name>.required`.
+    Declined(String),
+    /// The process does not advertise the capability, or could not be started
+    /// at all. Like git, the caller falls back to `clean`/`smudge` if set, and
+    /// otherwise handles it like [`Declined`](Outcome::Declined).
+    Unavailable(String),
+}
+
+/// Extra per-blob metadata sent with requests to a long-running filter process.
+///
+/// git sends `ref=`, `treeish=` and `blob=` with smudge requests during checkout
+/// so filters such as git-lfs can make smarter decisions. `blob=` is filled in
+/// automatically; the checkout target is not known to the filter, so callers
+/// provide it through [`ProcessFilterRegistration::set_metadata`](crate::ProcessFilterRegistration::set_metadata).
+#[derive(Debug, Clone, Default)]
+pub struct RequestMetadata {
+    /// Sent as `ref=` with smudge requests, e.g. `refs/heads/main`.
+    pub ref_name: Option<String>,
+    /// Sent as `treeish=` with smudge requests: the commit or tree being checked out.
+    pub treeish: Option<Oid>,
+    /// Additional `key=value` pairs sent with every request.
+    pub extra: Vec<(String, String)>,
+}
+
+impl RequestMetadata {
+    /// Check that every key and value can be sent as a pkt-line `key=value` line.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let reserved = ["command", "pathname", "ref", "treeish", "blob", "can-delay"];
+        if let Some(ref_name) = &self.ref_name {
+            check_value("ref", ref_name)?;
+        }
+        for (key, value) in &self.extra {
+            if key.is_empty() || key.contains(['=', '\n']) {
+                return Err(Error::from_str(&format!("invalid metadata key {:?}", key)));
+            }
+            if reserved.contains(&key.as_str()) {
+                return Err(Error::from_str(&format!(
+                    "metadata key '{}' is reserved",
+                    key
+                )));
+            }
+            check_value(key, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_value(key: &str, value: &str) -> Result<(), Error> {
+    if value.contains('\n') || value.len() + key.len() + 2 > pkt_line::MAX_PACKET_DATA {
+        return Err(Error::from_str(&format!(
+            "invalid value for metadata key '{}'",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// What a long-running filter process agreed to in its handshake.
+///
+/// Returned by [`ProcessFilterRegistration::process_capabilities`](crate::ProcessFilterRegistration::process_capabilities).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessCapabilities {
+    /// Protocol version agreed in the handshake.
+    pub version: u32,
+    /// Capabilities advertised by the process, in the order it sent them.
+    /// May include capabilities this crate does not know about.
+    pub capabilities: Vec<String>,
+}
+
+impl ProcessCapabilities {
+    /// Whether the process advertised `capability`.
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Whether the process handles clean (worktree → ODB) requests.
+    pub fn clean(&self) -> bool {
+        self.supports("clean")
+    }
+
+    /// Whether the process handles smudge (ODB → worktree) requests.
+    pub fn smudge(&self) -> bool {
+        self.supports("smudge")
+    }
+
+    /// Whether the process supports delayed checkout. git-lfs added this in 2.2.
+    pub fn delay(&self) -> bool {
+        self.supports("delay")
+    }
+
+    /// Advertised capabilities other than `clean`, `smudge` and `delay`.
+    pub fn unknown(&self) -> impl Iterator<Item = &str> {
+        self.capabilities
+            .iter()
+            .map(String::as_str)
+            .filter(|c| !["clean", "smudge", "delay"].contains(c))
+    }
+}
+
+/// How a long-running filter session ended.
+///
+/// Returned by [`ProcessFilterRegistration::shutdown`](crate::ProcessFilterRegistration::shutdown).
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownReport {
+    /// Everything the process wrote to stderr, including output from
+    /// instances that crashed and were restarted (capped at 1MB).
+    pub stderr: Vec<u8>,
+    /// Exit status of the last process, or `None` if no process was running.
+    pub status: Option<ExitStatus>,
+    /// Whether the process had to be killed because it did not exit within
+    /// the grace period after its stdin was closed.
+    pub killed: bool,
+}
+
+impl ShutdownReport {
+    /// Whether the session ended cleanly: the process (if any) exited on its
+    /// own with a zero status.
+    pub fn success(&self) -> bool {
+        !self.killed && self.status.is_none_or(|status| status.success())
+    }
+
+    /// Combine the reports of several processes: stderr is concatenated and
+    /// the first unsuccessful status is kept.
+    pub(crate) fn merge(mut self, other: ShutdownReport) -> ShutdownReport {
+        self.stderr.extend_from_slice(&other.stderr);
+        if self.status.is_none_or(|status| status.success()) && other.status.is_some() {
+            self.status = other.status;
+        }
+        self.killed |= other.killed;
+        self
+    }
+}
+
+/// A child process with piped stdio. Its stderr is forwarded as it arrives
+/// (like git does) and a copy is kept, up to [`STDERR_LIMIT`], in a shared log.
+///
+/// Shared by long-running filter processes and LFS transfer agents.
+pub(crate) struct Spawned {
+    pub(crate) program: String,
+    pub(crate) child: Child,
+    stderr_thread: Option<JoinHandle<()>>,
+}
+
+impl Spawned {
+    /// Start `program` with `args` in `workdir`, inheriting the environment.
+    pub(crate) fn start(
+        program: &str,
+        args: &[String],
+        workdir: Option<&Path>,
+        stderr_log: Arc<Mutex<Vec<u8>>>,
+    ) -> io::Result<Self> {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if let Some(dir) = workdir {
+            command.current_dir(dir);
+        }
+
+        let mut child = command
+            .spawn()
+            .inspect_err(|_| stats::note_failure(FailureKind::Spawn))?;
+        stats::note_spawn();
+        trace::event(|| format!("spawned {:?} {:?} (pid {})", program, args, child.id()));
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let stderr_thread = std::thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            while let Ok(n) = stderr.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let _ = io::stderr().write_all(&buf[..n]);
+                let mut log = stderr_log.lock().unwrap_or_else(|e| e.into_inner());
+                let keep = n.min(STDERR_LIMIT.saturating_sub(log.len()));
+                log.extend_from_slice(&buf[..keep]);
+            }
+        });
+
+        Ok(Spawned {
+            program: program.to_string(),
+            child,
+            stderr_thread: Some(stderr_thread),
+        })
+    }
+
+    /// Wait up to `grace` for the process to exit (its stdin should already
+    /// be closed), killing it if it does not. Returns the exit status and
+    /// whether it was killed.
+    pub(crate) fn stop(&mut self, grace: Duration) -> (Option<ExitStatus>, bool) {
+        let start = Instant::now();
+        let mut killed = false;
+        let status = loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if start.elapsed() < grace => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Ok(None) => {
+                    killed = true;
+                    let _ = self.child.kill();
+                    break self.child.wait().ok();
+                }
+                Err(_) => break None,
+            }
+        };
+
+        trace::event(|| match status {
+            _ if killed => format!("'{}' killed after {:?}", self.program, grace),
+            Some(status) => format!("'{}' exited with {}", self.program, status),
+            None => format!("'{}' exited with unknown status", self.program),
+        });
+
+        // Let the stderr thread drain what is left, unless something else
+        // (e.g. a grandchild) keeps the pipe open
+        if let Some(thread) = self.stderr_thread.take() {
+            while !thread.is_finished() && start.elapsed() < grace {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            if thread.is_finished() {
+                let _ = thread.join();
+            }
+        }
+
+        (status, killed)
+    }
+}
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        // No-op if the process already exited via `stop`
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A spawned filter process that has completed the handshake.
+struct LongRunningProcess {
+    /// `None` once stdin has been closed to ask the process to exit.
+    stdin: Option<BufWriter<Traced<ChildStdin>>>,
+    stdout: BufReader<Traced<ChildStdout>>,
+    capabilities: Vec<String>,
+    /// Capabilities the process has aborted with `status=abort`.
+    aborted: Vec<String>,
+    process: Spawned,
+}
+
+impl LongRunningProcess {
+    fn start(
+        cmd: &str,
+        workdir: Option<&Path>,
+        stderr_log: Arc<Mutex<Vec<u8>>>,
+    ) -> io::Result<Self> {
+        let (program, args) = ProcessFilter::parse_command(cmd, "");
+        if program.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+        }
+
+        let mut spawned = Spawned::start(&program, &args, workdir, stderr_log)?;
+        let stdin = spawned.child.stdin.take().expect("stdin is piped");
+        let stdout = spawned.child.stdout.take().expect("stdout is piped");
+
+        // From here on `process` owns the child, so a failed handshake kills it on drop
+        let mut process = LongRunningProcess {
+            stdin: Some(BufWriter::new(Traced::new(stdin, &program, '>'))),
+            stdout: BufReader::new(Traced::new(stdout, &program, '<')),
+            capabilities: Vec::new(),
+            aborted: Vec::new(),
+            process: spawned,
+        };
+        process.handshake()?;
+        Ok(process)
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut BufWriter<Traced<ChildStdin>>> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin already closed"))
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let stdin = self.stdin()?;
+        pkt_line::write_list(stdin, &["git-filter-client", "version=2"])?;
+        stdin.flush()?;
+
+        let welcome = pkt_line::read_list(&mut self.stdout)?;
+        if welcome.first().map(String::as_str) != Some("git-filter-server") {
+            return Err(protocol_error(format!(
+                "unexpected welcome message {:?}",
+                welcome
+            )));
+        }
+        if !welcome
+            .iter()
+            .any(|line| *line == format!("version={}", PROTOCOL_VERSION))
+        {
+            return Err(protocol_error(format!(
+                "unsupported protocol version {:?}",
+                welcome
+            )));
+        }
+
+        let stdin = self.stdin()?;
+        pkt_line::write_list(stdin, OFFERED_CAPABILITIES)?;
+        stdin.flush()?;
+
+        for line in pkt_line::read_list(&mut self.stdout)? {
+            match line.strip_prefix("capability=") {
+                Some(capability) => self.capabilities.push(capability.to_string()),
+                None => {
+                    return Err(protocol_error(format!(
+                        "unexpected capability line {:?}",
+                        line
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> ProcessCapabilities {
+        ProcessCapabilities {
+            version: PROTOCOL_VERSION,
+            capabilities: self.capabilities.clone(),
+        }
+    }
+
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability) && !self.has_aborted(capability)
+    }
+
+    fn has_aborted(&self, capability: &str) -> bool {
+        self.aborted.iter().any(|c| c == capability)
+    }
+
+    /// Send one request made of `header` lines (`command=...`, `pathname=...`,
+    /// metadata) followed by `input`. Any I/O or protocol error means the
+    /// process is unusable and must be restarted.
+    fn request(&mut self, header: &[String], input: &[u8]) -> io::Result<Reply> {
+        let header: Vec<&str> = header.iter().map(String::as_str).collect();
+        let stdin = self.stdin()?;
+        pkt_line::write_list(stdin, &header)?;
+        pkt_line::write_content(stdin, input)?;
+        stdin.flush()?;
+
+        match read_status(&pkt_line::read_list(&mut self.stdout)?)? {
+            Status::Success => {}
+            Status::Error => return Ok(Reply::Error),
+            Status::Abort => return Ok(Reply::Abort),
+        }
+
+        let output = pkt_line::read_content(&mut self.stdout)?;
+        // The filter may revoke a success status after sending content
+        let trailer = pkt_line::read_list(&mut self.stdout)?;
+        if trailer.is_empty() {
+            return Ok(Reply::Success(output));
+        }
+        match read_status(&trailer)? {
+            Status::Success => Ok(Reply::Success(output)),
+            Status::Error => Ok(Reply::Error),
+            Status::Abort => Ok(Reply::Abort),
+        }
+    }
+
+    /// Close stdin and wait up to `grace` for the process to exit, killing it
+    /// if it does not. Returns the exit status and whether it was killed.
+    fn shutdown(&mut self, grace: Duration) -> (Option<ExitStatus>, bool) {
+        // Closing stdin tells the process there are no more requests
+        drop(self.stdin.take());
+        self.process.stop(grace)
+    }
+}
+
+enum Status {
+    Success,
+    Error,
+    Abort,
+}
+
+fn read_status(lines: &[String]) -> io::Result<Status> {
+    // The last status line wins, as in git
+    let status = lines
+        .iter()
+        .rev()
+        .find_map(|line| line.strip_prefix("status="));
+    match status {
+        Some("success") => Ok(Status::Success),
+        Some("error") => Ok(Status::Error),
+        Some("abort") => Ok(Status::Abort),
+        _ => Err(protocol_error(format!("unexpected status {:?}", lines))),
+    }
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Default)]
+struct DriverState {
+    process: Option<LongRunningProcess>,
+    restarts: u32,
+    gave_up: bool,
+    /// Whether a process has ever started.
+    started: bool,
+    /// Why the first start failed; no later start is attempted.
+    start_failure: Option<String>,
+}
+
+impl DriverState {
+    /// Return the running process, spawning it first if necessary.
+    fn running(
+        &mut self,
+        command: &str,
+        workdir: Option<&Path>,
+        stderr: Arc<Mutex<Vec<u8>>>,
+    ) -> io::Result<&mut LongRunningProcess> {
+        if self.process.is_none() {
+            self.process = Some(LongRunningProcess::start(command, workdir, stderr)?);
+            self.started = true;
+        }
+        Ok(self.process.as_mut().expect("process was just started"))
+    }
+}
+
+/// Owns the long-running process for one registered filter, restarts it
+/// when it dies and stops it when dropped.
+pub(crate) struct ProcessDriver {
+    command: String,
+    /// Repository workdir, used when the process is started outside of a
+    /// filter request (e.g. to query its capabilities).
+    workdir: Option<PathBuf>,
+    state: Mutex<DriverState>,
+    metadata: Mutex<RequestMetadata>,
+    /// stderr of every process instance started by this driver.
+    stderr: Arc<Mutex<Vec<u8>>>,
+}
+
+impl ProcessDriver {
+    pub(crate) fn new(command: &str, workdir: Option<&Path>) -> Self {
+        ProcessDriver {
+            command: command.to_string(),
+            workdir: workdir.map(Path::to_path_buf),
+            state: Mutex::new(DriverState::default()),
+            metadata: Mutex::new(RequestMetadata::default()),
+            stderr: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The command that starts the process.
+    pub(crate) fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Replace the metadata sent with subsequent requests.
+    pub(crate) fn set_metadata(&self, metadata: RequestMetadata) -> Result<(), Error> {
+        metadata.validate()?;
+        *self.metadata.lock().unwrap_or_else(|e| e.into_inner()) = metadata;
+        Ok(())
+    }
+
+    /// Build the request lines for one blob, in the order git sends them.
+    ///
+    /// Only smudge requests carry `blob=`, so clean requests never hash their
+    /// content. `FilterSource` does not expose the id of the blob being
+    /// smudged, so it is hashed here, once per blob: retries reuse the header.
+    fn request_header(
+        &self,
+        capability: &str,
+        path: &str,
+        input: &[u8],
+    ) -> Result<Vec<String>, Error> {
+        let metadata = self.metadata.lock().unwrap_or_else(|e| e.into_inner());
+        let mut header = vec![
+            format!("command={}", capability),
+            format!("pathname={}", path),
+        ];
+        if capability == "smudge" {
+            if let Some(ref_name) = &metadata.ref_name {
+                header.push(format!("ref={}", ref_name));
+            }
+            if let Some(treeish) = metadata.treeish {
+                header.push(format!("treeish={}", treeish));
+            }
+            // Smudge input is the blob content as stored in the ODB
+            header.push(format!(
+                "blob={}",
+                Oid::hash_object(ObjectType::Blob, input)?
+            ));
+        }
+        for (key, value) in &metadata.extra {
+            header.push(format!("{}={}", key, value));
+        }
+        Ok(header)
+    }
+
+    /// Capabilities of the running process, starting it first if necessary.
+    pub(crate) fn capabilities(&self) -> Result<ProcessCapabilities, Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let stderr = Arc::clone(&self.stderr);
+        state
+            .running(&self.command, self.workdir.as_deref(), stderr)
+            .map(|process| process.capabilities())
+            .map_err(|e| Error::from_str(&format!("failed to start '{}': {}", self.command, e)))
+    }
+
+    /// Stop the running process (if any) and report how the session ended.
+    ///
+    /// A later request starts a new process.
+    pub(crate) fn shutdown(&self) -> ShutdownReport {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (status, killed) = match state.process.take() {
+            Some(mut process) => process.shutdown(SHUTDOWN_GRACE),
+            None => (None, false),
+        };
+        let stderr = std::mem::take(&mut *self.stderr.lock().unwrap_or_else(|e| e.into_inner()));
+        ShutdownReport {
+            stderr,
+            status,
+            killed,
+        }
+    }
+
+    /// Filter `input` through the process using `capability` (`clean` or `smudge`).
+    ///
+    /// If the process dies or breaks the protocol it is restarted and the blob
+    /// is retried once. Once [`MAX_RESTARTS`] is exhausted every further
+    /// request is declined without spawning the process again. A process that
+    /// never started, or a capability it does not advertise, makes the request
+    /// [`Unavailable`](Outcome::Unavailable).
+    ///
+    /// `status=error` declines only this blob. `status=abort` declines this
+    /// blob and every later request for the same capability without sending
+    /// it to the process (until the process is restarted). As in git,
+    /// `filter.<name>.required` decides whether declined blobs fail or pass
+    /// through.
+    pub(crate) fn apply(
+        &self,
+        capability: &str,
+        path: &str,
+        workdir: Option<&Path>,
+        input: &[u8],
+    ) -> Result<Outcome, Error> {
+        let header = self.request_header(capability, path, input)?;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut retried = false;
+
+        loop {
+            if let Some(reason) = &state.start_failure {
+                return Ok(Outcome::Unavailable(reason.clone()));
+            }
+            if state.gave_up {
+                return Ok(Outcome::Declined(format!(
+                    "'{}' was restarted {} times and is no longer used",
+                    self.command, MAX_RESTARTS
+                )));
+            }
+
+            if state.process.is_some() {
+                stats::note_reuse();
+            }
+            let stderr = Arc::clone(&self.stderr);
+            let attempt = state
+                .running(&self.command, workdir, stderr)
+                .and_then(|process| {
+                    if !process.supports(capability) {
+                        return Ok(None);
+                    }
+                    let reply = process.request(&header, input)?;
+                    if let Reply::Abort = reply {
+                        // Like git, stop sending this command to this process
+                        process.aborted.push(capability.to_string());
+                    }
+                    Ok(Some(reply))
+                });
+
+            match attempt {
+                Ok(Some(Reply::Success(output))) => return Ok(Outcome::Filtered(output)),
+                Ok(Some(Reply::Error)) => {
+                    stats::note_failure(FailureKind::Filter);
+                    return Ok(Outcome::Declined(format!(
+                        "'{}' failed to {} '{}'",
+                        self.command, capability, path
+                    )));
+                }
+                Ok(Some(Reply::Abort)) => {
+                    stats::note_failure(FailureKind::Filter);
+                    return Ok(Outcome::Declined(format!(
+                        "'{}' aborted {} of '{}'; further {} requests are not sent to it",
+                        self.command, capability, path, capability
+                    )));
+                }
+                Ok(None) => {
+                    let aborted = state
+                        .process
+                        .as_ref()
+                        .is_some_and(|p| p.has_aborted(capability));
+                    if aborted {
+                        return Ok(Outcome::Declined(format!(
+                            "'{}' aborted {} requests",
+                            self.command, capability
+                        )));
+                    }
+                    return Ok(Outcome::Unavailable(format!(
+                        "'{}' does not support {}",
+                        self.command, capability
+                    )));
+                }
+                Err(e) if !state.started => {
+                    let reason = format!("failed to start '{}': {}", self.command, e);
+                    trace::event(|| reason.clone());
+                    state.start_failure = Some(reason.clone());
+                    return Ok(Outcome::Unavailable(reason));
+                }
+                Err(e) => {
+                    // Dropping the process kills it
+                    state.process = None;
+                    stats::note_failure(FailureKind::Protocol);
+                    trace::event(|| format!("'{}' failed: {}", self.command, e));
+
+                    if state.restarts >= MAX_RESTARTS {
+                        state.gave_up = true;
+                        eprintln!(
+                            "[git2-process-filter] {} failed: {}; giving up after {} restarts",
+                            self.command, e, MAX_RESTARTS
+                        );
+                        continue;
+                    }
+                    if retried {
+                        return Ok(Outcome::Declined(format!(
+                            "'{}' failed to {} '{}': {}",
+                            self.command, capability, path, e
+                        )));
+                    }
+
+                    state.restarts += 1;
+                    retried = true;
+                    eprintln!(
+                        "[git2-process-filter] {} failed while filtering '{}': {}; restarting ({}/{})",
+                        self.command, path, e, state.restarts, MAX_RESTARTS
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ProcessDriver {
+    fn drop(&mut self) {
+        let report = self.shutdown();
+        if !report.success() {
+            eprintln!(
+                "[git2-process-filter] {} did not exit cleanly: {}",
+                self.command,
+                match report.status {
+                    _ if report.killed => "killed after shutdown timeout".to_string(),
+                    Some(status) => status.to_string(),
+                    None => "unknown status".to_string(),
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_header_clean() {
+        let driver = ProcessDriver::new("unused", None);
+        let header = driver.request_header("clean", "a.bin", b"data").unwrap();
+        // No blob id: clean requests are not hashed
+        assert_eq!(header, vec!["command=clean", "pathname=a.bin"]);
+    }
+
+    #[test]
+    fn test_request_header_smudge_metadata() {
+        let driver = ProcessDriver::new("unused", None);
+        let treeish = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        driver
+            .set_metadata(RequestMetadata {
+                ref_name: Some("refs/heads/main".to_string()),
+                treeish: Some(treeish),
+                extra: vec![("x-job".to_string(), "42".to_string())],
+            })
+            .unwrap();
+
+        let header = driver.request_header("smudge", "a.bin", b"data").unwrap();
+        let blob = Oid::hash_object(ObjectType::Blob, b"data").unwrap();
+        assert_eq!(
+            header,
+            vec![
+                "command=smudge".to_string(),
+                "pathname=a.bin".to_string(),
+                "ref=refs/heads/main".to_string(),
+                format!("treeish={}", treeish),
+                format!("blob={}", blob),
+                "x-job=42".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_capabilities() {
+        let caps = ProcessCapabilities {
+            version: 2,
+            capabilities: vec!["smudge".to_string(), "x-resume".to_string()],
+        };
+        assert!(!caps.clean());
+        assert!(caps.smudge());
+        assert!(!caps.delay());
+        assert_eq!(caps.unknown().collect::<Vec<_>>(), vec!["x-resume"]);
+    }
+
+    #[test]
+    fn test_metadata_validation() {
+        let extra = |key: &str, value: &str| RequestMetadata {
+            extra: vec![(key.to_string(), value.to_string())],
+            ..Default::default()
+        };
+        assert!(extra("x-job", "42").validate().is_ok());
+        assert!(extra("pathname", "x").validate().is_err());
+        assert!(extra("a=b", "x").validate().is_err());
+        assert!(extra("key", "line\nbreak").validate().is_err());
+        assert!(RequestMetadata {
+            ref_name: Some("refs/heads/\n".to_string()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Round-trip verification of clean output, for
+//! `filter.<name>.verifyroundtrip`.
+
+use crate::{stats, FilterMode, ProcessFilter};
+use git2::Error;
+use std::path::Path;
+
+/// Environment variable that turns verification on for every filter, e.g. in
+/// CI.
+pub(crate) const ENV: &str = "GIT2_PROCESS_FILTER_VERIFY_ROUNDTRIP";
+
+/// Bytes shown on each side of a difference.
+const SHOWN: usize = 32;
+
+pub(crate) fn enabled_by_env() -> bool {
+    std::env::var(ENV)
+        .map(|value| !matches!(value.as_str(), "" | "0" | "false"))
+        .unwrap_or(false)
+}
+
+/// Check that `cleaned`, the clean output of `input`, smudges back to
+/// `input`, and that cleaning it again leaves it unchanged. Filters without a
+/// smudge side are only checked for the latter. Nothing is checked when the
+/// clean did not run: the filter has no clean side, or declined the blob.
+pub(crate) fn verify(
+    filter: &ProcessFilter,
+    path: &str,
+    workdir: Option<&Path>,
+    input: &[u8],
+    cleaned: &[u8],
+) -> Result<(), Error> {
+    if !filter.cleans() || stats::declined() {
+        return Ok(());
+    }
+    if filter.smudges() {
+        let smudged = filter.filter(FilterMode::ToWorktree, path, workdir, cleaned)?;
+        if smudged != input {
+            return Err(Error::from_str(&format!(
+                "filter '{}' does not round-trip '{}': smudging the clean output {}",
+                filter.name,
+                path,
+                diff_summary(input, &smudged)
+            )));
+        }
+    }
+    let recleaned = filter.filter(FilterMode::ToOdb, path, workdir, cleaned)?;
+    if recleaned != cleaned {
+        return Err(Error::from_str(&format!(
+            "filter '{}' clean is not idempotent for '{}': cleaning the clean output {}",
+            filter.name,
+            path,
+            diff_summary(cleaned, &recleaned)
+        )));
+    }
+    Ok(())
+}
+
+/// Describe how `actual` differs from `expected`: sizes, and the first
+/// difference with its line number and some context.
+fn diff_summary(expected: &[u8], actual: &[u8]) -> String {
+    let at = expected
+        .iter()
+        .zip(actual)
+        .position(|(a, b)| a != b)
+        .unwrap_or(expected.len().min(actual.len()));
+    let line = expected[..at].iter().filter(|&&b| b == b'\n').count() + 1;
+    let start = at.saturating_sub(SHOWN / 2);
+    let excerpt = |data: &[u8]| {
+        let end = data.len().min(start + SHOWN);
+        let shown = data.get(start..end).unwrap_or_default().escape_ascii();
+        let ellipsis = if end < data.len() { "..." } else { "" };
+        format!("\"{}\"{}", shown, ellipsis)
+    };
+    format!(
+        "gives {} bytes instead of {}; first difference at byte {} (line {}): expected {}, got {}",
+        actual.len(),
+        expected.len(),
+        at,
+        line,
+        excerpt(expected),
+        excerpt(actual)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_summary() {
+        assert_eq!(
+            diff_summary(b"one\ntwo\n", b"one\nTWO\n"),
+            "gives 8 bytes instead of 8; first difference at byte 4 (line 2): \
+             expected \"one\\ntwo\\n\", got \"one\\nTWO\\n\""
+        );
+        // Truncated output differs where it ends
+        let summary = diff_summary(&[b'a'; 100], &[b'a'; 60]);
+        assert!(
+            summary.starts_with("gives 60 bytes instead of 100; first difference at byte 60"),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with("got \"aaaaaaaaaaaaaaaa\""), "{}", summary);
+    }
+}
@@ -0,0 +1,404 @@
+//! Filter side of git's long-running filter protocol.
+//!
+//! Implement [`LongRunningFilter`] and call [`run`] from `main` to get a
+//! program usable as `filter.<name>.process`, both by the git CLI and by
+//! [`register_process_filter`](crate::register_process_filter).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use git2_process_filter::server::{self, FilterError, LongRunningFilter, Request};
+//!
+//! struct Upper;
+//!
+//! impl LongRunningFilter for Upper {
+//!     fn clean(&mut self, _request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+//!         Ok(input.to_ascii_uppercase())
+//!     }
+//!
+//!     fn smudge(&mut self, _request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+//!         Ok(input.to_ascii_lowercase())
+//!     }
+//! }
+//!
+//! fn main() -> std::io::Result<()> {
+//!     // filter.upper.process = /path/to/this/binary
+//!     server::run(Upper)
+//! }
+//! ```
+
+use crate::pkt_line;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+/// Why a filter could not process a blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterError {
+    /// Reply `status=error`: this blob failed, later requests are unaffected.
+    /// The message is written to stderr.
+    Error(String),
+    /// Reply `status=abort`: refuse this and all further requests for the
+    /// same command. The message is written to stderr.
+    Abort(String),
+}
+
+/// A single clean or smudge request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// `clean` or `smudge`.
+    pub command: String,
+    /// Path of the file relative to the repository root.
+    pub pathname: String,
+    /// Whether the client allows this smudge to be delayed (`can-delay=1`).
+    pub can_delay: bool,
+    /// Every other `key=value` pair sent with the request, such as `ref`,
+    /// `treeish` and `blob`.
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Look up a metadata value, e.g. `request.get("blob")`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn parse(lines: Vec<String>) -> io::Result<Self> {
+        let mut command = None;
+        let mut pathname = None;
+        let mut can_delay = false;
+        let mut metadata = Vec::new();
+
+        for line in lines {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| protocol_error(format!("malformed request line {:?}", line)))?;
+            match key {
+                "command" => command = Some(value.to_string()),
+                "pathname" => pathname = Some(value.to_string()),
+                "can-delay" => can_delay = value == "1",
+                _ => metadata.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(Request {
+            command: command.ok_or_else(|| protocol_error("request without command".into()))?,
+            pathname: pathname.unwrap_or_default(),
+            can_delay,
+            metadata,
+        })
+    }
+}
+
+/// A filter served over git's long-running filter protocol.
+pub trait LongRunningFilter {
+    /// Convert worktree content to what is stored in the repository.
+    fn clean(&mut self, request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError>;
+
+    /// Convert repository content to what is written to the worktree.
+    fn smudge(&mut self, request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError>;
+
+    /// Whether to advertise the `delay` capability. Defaults to `false`.
+    fn supports_delay(&self) -> bool {
+        false
+    }
+
+    /// Called for smudge requests with `can-delay=1`. Returning `true` answers
+    /// `status=delayed`; [`smudge`](Self::smudge) is then called with the same
+    /// content once the client asks for the blob again, which lets the filter
+    /// start work (e.g. a batch download) for many blobs before producing any.
+    fn delay(&mut self, _request: &Request) -> bool {
+        false
+    }
+}
+
+/// Serve `filter` over stdin/stdout until the client closes stdin.
+pub fn run<F: LongRunningFilter>(mut filter: F) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    serve(&mut filter, stdin.lock(), stdout.lock())
+}
+
+/// Serve `filter` over arbitrary streams until `input` reaches end of file.
+pub fn serve<F, R, W>(filter: &mut F, input: R, output: W) -> io::Result<()>
+where
+    F: LongRunningFilter + ?Sized,
+    R: Read,
+    W: Write,
+{
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+
+    handshake(filter, &mut input, &mut output)?;
+
+    // Delayed smudges, keyed by pathname, waiting for the client to ask again
+    let mut delayed: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut aborted: Vec<String> = Vec::new();
+
+    loop {
+        let lines = match pkt_line::read_list(&mut input) {
+            Ok(lines) => lines,
+            // The client closes our stdin when it has no more requests
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let request = Request::parse(lines)?;
+
+        if request.command == "list_available_blobs" {
+            let paths: Vec<String> = delayed.keys().map(|p| format!("pathname={}", p)).collect();
+            let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+            pkt_line::write_list(&mut output, &paths)?;
+            pkt_line::write_list(&mut output, &["status=success"])?;
+            output.flush()?;
+            continue;
+        }
+
+        let mut content = pkt_line::read_content(&mut input)?;
+
+        if aborted.contains(&request.command) {
+            pkt_line::write_list(&mut output, &["status=abort"])?;
+            output.flush()?;
+            continue;
+        }
+
+        let result = match request.command.as_str() {
+            "clean" => filter.clean(&request, &content),
+            "smudge" => {
+                if request.can_delay && filter.supports_delay() && filter.delay(&request) {
+                    delayed.insert(request.pathname.clone(), content);
+                    pkt_line::write_list(&mut output, &["status=delayed"])?;
+                    output.flush()?;
+                    continue;
+                }
+                // A delayed blob is requested again without content
+                if let Some(stored) = delayed.remove(&request.pathname) {
+                    content = stored;
+                }
+                filter.smudge(&request, &content)
+            }
+            other => Err(FilterError::Error(format!("unknown command '{}'", other))),
+        };
+
+        match result {
+            Ok(data) => {
+                pkt_line::write_list(&mut output, &["status=success"])?;
+                pkt_line::write_content(&mut output, &data)?;
+                // Empty trailing status list: keep "success"
+                pkt_line::write_flush(&mut output)?;
+            }
+            Err(FilterError::Error(message)) => {
+                eprintln!("{}: {}", request.pathname, message);
+                pkt_line::write_list(&mut output, &["status=error"])?;
+            }
+            Err(FilterError::Abort(message)) => {
+                eprintln!("{}: {}", request.pathname, message);
+                aborted.push(request.command.clone());
+                pkt_line::write_list(&mut output, &["status=abort"])?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+fn handshake<F, R, W>(filter: &F, input: &mut R, output: &mut W) -> io::Result<()>
+where
+    F: LongRunningFilter + ?Sized,
+    R: Read,
+    W: Write,
+{
+    let welcome = pkt_line::read_list(input)?;
+    if welcome.first().map(String::as_str) != Some("git-filter-client") {
+        return Err(protocol_error(format!(
+            "unexpected welcome message {:?}",
+            welcome
+        )));
+    }
+    if !welcome.iter().any(|line| line == "version=2") {
+        return Err(protocol_error(format!(
+            "unsupported protocol version {:?}",
+            welcome
+        )));
+    }
+    pkt_line::write_list(output, &["git-filter-server", "version=2"])?;
+    output.flush()?;
+
+    let offered = pkt_line::read_list(input)?;
+    let mut ours = vec!["capability=clean", "capability=smudge"];
+    if filter.supports_delay() {
+        ours.push("capability=delay");
+    }
+    ours.retain(|c| offered.iter().any(|o| o == c));
+    pkt_line::write_list(output, &ours)?;
+    output.flush()
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    struct Upper {
+        delay: bool,
+    }
+
+    impl LongRunningFilter for Upper {
+        fn clean(&mut self, request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+            match request.pathname.as_str() {
+                "error.txt" => Err(FilterError::Error("bad".into())),
+                "abort.txt" => Err(FilterError::Abort("giving up".into())),
+                _ => Ok(input.to_ascii_uppercase()),
+            }
+        }
+
+        fn smudge(&mut self, _request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+            Ok(input.to_ascii_lowercase())
+        }
+
+        fn supports_delay(&self) -> bool {
+            self.delay
+        }
+
+        fn delay(&mut self, _request: &Request) -> bool {
+            true
+        }
+    }
+
+    /// Client side of the conversation: handshake followed by `requests`.
+    fn client_input(capabilities: &[&str], requests: &[(&[&str], Option<&[u8]>)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        pkt_line::write_list(&mut buf, &["git-filter-client", "version=2"]).unwrap();
+        pkt_line::write_list(&mut buf, capabilities).unwrap();
+        for (lines, content) in requests {
+            pkt_line::write_list(&mut buf, lines).unwrap();
+            if let Some(content) = content {
+                pkt_line::write_content(&mut buf, content).unwrap();
+            }
+        }
+        buf
+    }
+
+    fn serve_all(filter: &mut Upper, input: Vec<u8>) -> Cursor<Vec<u8>> {
+        let mut output = Vec::new();
+        serve(filter, Cursor::new(input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["git-filter-server", "version=2"]
+        );
+        output
+    }
+
+    #[test]
+    fn test_serve_clean_and_smudge() {
+        let input = client_input(
+            &["capability=clean", "capability=smudge", "capability=delay"],
+            &[
+                (&["command=clean", "pathname=a.txt"], Some(b"hello")),
+                (
+                    &["command=smudge", "pathname=a.txt", "blob=abc"],
+                    Some(b"HELLO"),
+                ),
+            ],
+        );
+        let mut output = serve_all(&mut Upper { delay: false }, input);
+
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["capability=clean", "capability=smudge"]
+        );
+        for expected in [&b"HELLO"[..], &b"hello"[..]] {
+            assert_eq!(
+                pkt_line::read_list(&mut output).unwrap(),
+                vec!["status=success"]
+            );
+            assert_eq!(pkt_line::read_content(&mut output).unwrap(), expected);
+            assert!(pkt_line::read_list(&mut output).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_serve_error_and_abort() {
+        let input = client_input(
+            &["capability=clean", "capability=smudge"],
+            &[
+                (&["command=clean", "pathname=error.txt"], Some(b"x")),
+                (&["command=clean", "pathname=ok.txt"], Some(b"x")),
+                (&["command=clean", "pathname=abort.txt"], Some(b"x")),
+                (&["command=clean", "pathname=ok.txt"], Some(b"x")),
+            ],
+        );
+        let mut output = serve_all(&mut Upper { delay: false }, input);
+        pkt_line::read_list(&mut output).unwrap();
+
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=error"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=success"]
+        );
+        assert_eq!(pkt_line::read_content(&mut output).unwrap(), b"X");
+        pkt_line::read_list(&mut output).unwrap();
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=abort"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=abort"]
+        );
+    }
+
+    #[test]
+    fn test_serve_delay() {
+        let input = client_input(
+            &["capability=clean", "capability=smudge", "capability=delay"],
+            &[
+                (
+                    &["command=smudge", "pathname=a.txt", "can-delay=1"],
+                    Some(b"HELLO"),
+                ),
+                (&["command=list_available_blobs"], None),
+                (&["command=smudge", "pathname=a.txt"], Some(b"")),
+            ],
+        );
+        let mut output = serve_all(&mut Upper { delay: true }, input);
+
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["capability=clean", "capability=smudge", "capability=delay"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=delayed"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["pathname=a.txt"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=success"]
+        );
+        assert_eq!(
+            pkt_line::read_list(&mut output).unwrap(),
+            vec!["status=success"]
+        );
+        assert_eq!(pkt_line::read_content(&mut output).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_serve_rejects_bad_handshake() {
+        let mut input = Vec::new();
+        pkt_line::write_list(&mut input, &["git-filter-client", "version=3"]).unwrap();
+        let result = serve(&mut Upper { delay: false }, Cursor::new(input), Vec::new());
+        assert!(result.is_err());
+    }
+}
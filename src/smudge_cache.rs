@@ -0,0 +1,256 @@
+//! On-disk cache of smudge output, for `filter.<name>.smudgecache`.
+
+use crate::trace;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Cache size when `filter.<name>.smudgecachesize` is not set (1 GiB).
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Bytes of the SHA-256 of the content stored in front of it.
+const HASH_LEN: usize = 32;
+
+/// Smudge output keyed by the SHA-256 of the filter name, its command and
+/// the smudged input, stored as `<dir>/aa/<rest of the key>`. For drivers
+/// that see the path (a `%f` command or a long-running process), the key
+/// also includes the path, see [`keyed_by_path`](Self::keyed_by_path).
+///
+/// Each entry starts with the SHA-256 of its content, so entries that are
+/// truncated or corrupted are dropped instead of checked out. Reading an
+/// entry updates its modification time, and the least recently used entries
+/// are removed once the cache grows beyond its size limit. Several processes
+/// can share the directory: entries are written to a temporary file and
+/// renamed into place.
+pub(crate) struct SmudgeCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Filter name and command, hashed into every key.
+    scope: Vec<u8>,
+    /// Whether the path is hashed into every key.
+    by_path: bool,
+    /// Bytes stored, as of the last scan plus what was added since.
+    size: AtomicU64,
+}
+
+impl SmudgeCache {
+    pub(crate) fn open(dir: &Path, max_size: u64, name: &str, command: &str) -> Self {
+        let mut scope = Vec::new();
+        for part in [name, command] {
+            scope.extend_from_slice(part.as_bytes());
+            scope.push(0);
+        }
+        let cache = SmudgeCache {
+            dir: dir.to_path_buf(),
+            max_size,
+            scope,
+            by_path: false,
+            size: AtomicU64::new(0),
+        };
+        let size = cache.entries().iter().map(|(_, len, _)| len).sum();
+        cache.size.store(size, Ordering::Relaxed);
+        cache
+    }
+
+    /// Key entries by path as well, for drivers whose output may depend on
+    /// it: the same content at two paths is then smudged separately.
+    pub(crate) fn keyed_by_path(mut self) -> Self {
+        self.by_path = true;
+        self
+    }
+
+    fn entry(&self, path: &str, input: &[u8]) -> PathBuf {
+        let mut hasher = Sha256::new().chain_update(&self.scope);
+        if self.by_path {
+            hasher.update(path);
+            hasher.update([0]);
+        }
+        let key = format!("{:x}", hasher.chain_update(input).finalize());
+        self.dir.join(&key[..2]).join(&key[2..])
+    }
+
+    /// The cached output for `input`, if there is an intact entry.
+    pub(crate) fn get(&self, path: &str, input: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.entry(path, input);
+        let data = fs::read(&entry).ok()?;
+        let intact = data.len() >= HASH_LEN
+            && Sha256::digest(&data[HASH_LEN..]).as_slice() == &data[..HASH_LEN];
+        if !intact {
+            eprintln!(
+                "[git2-process-filter] warning: dropping corrupt smudge cache entry {} for '{}'",
+                entry.display(),
+                path
+            );
+            if fs::remove_file(&entry).is_ok() {
+                self.size.fetch_sub(
+                    (data.len() as u64).min(self.size.load(Ordering::Relaxed)),
+                    Ordering::Relaxed,
+                );
+            }
+            return None;
+        }
+
+        // Mark the entry as recently used
+        if let Ok(file) = File::options().write(true).open(&entry) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        trace::event(|| format!("smudge cache hit for '{}' ({})", path, entry.display()));
+        Some(data[HASH_LEN..].to_vec())
+    }
+
+    /// Store `output` as the smudge output for `input`. Failures only warn:
+    /// the cache is an optimization.
+    pub(crate) fn put(&self, path: &str, input: &[u8], output: &[u8]) {
+        let entry = self.entry(path, input);
+        let mut data = Vec::with_capacity(HASH_LEN + output.len());
+        data.extend_from_slice(&Sha256::digest(output));
+        data.extend_from_slice(output);
+        if let Err(e) = write_atomic(&entry, &data) {
+            eprintln!(
+                "[git2-process-filter] warning: failed to cache smudge output for '{}': {}",
+                path, e
+            );
+            return;
+        }
+        let size = (HASH_LEN + output.len()) as u64;
+        if self.size.fetch_add(size, Ordering::Relaxed) + size > self.max_size {
+            self.evict();
+        }
+    }
+
+    /// Every entry with its size and last use.
+    fn entries(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        let Ok(dirs) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        dirs.flatten()
+            .filter_map(|dir| fs::read_dir(dir.path()).ok())
+            .flat_map(|entries| entries.flatten())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with(".tmp-"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((entry.path(), metadata.len(), modified))
+            })
+            .collect()
+    }
+
+    /// Remove the least recently used entries until the cache is at most
+    /// 90% of its limit, so that eviction does not run on every insert.
+    fn evict(&self) {
+        let mut entries = self.entries();
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        let target = self.max_size / 10 * 9;
+        entries.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut removed = 0;
+        for (entry, len, _) in entries {
+            if size <= target {
+                break;
+            }
+            if fs::remove_file(&entry).is_ok() {
+                size -= len;
+                removed += 1;
+            }
+        }
+        self.size.store(size, Ordering::Relaxed);
+        trace::event(|| {
+            format!(
+                "smudge cache {}: evicted {} entries, {} bytes left",
+                self.dir.display(),
+                removed,
+                size
+            )
+        });
+    }
+}
+
+/// Write a cache entry through a temporary file renamed into place, so that
+/// readers never see a partial entry.
+pub(crate) fn write_atomic(entry: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let parent = entry.parent().expect("cache entries are in a subdirectory");
+    fs::create_dir_all(parent)?;
+    let tmp = parent.join(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, entry).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_get_put() {
+        let td = TempDir::new().unwrap();
+        let cache = SmudgeCache::open(td.path(), DEFAULT_MAX_SIZE, "lfs", "git-lfs smudge");
+        assert_eq!(cache.get("a", b"pointer"), None);
+        cache.put("a", b"pointer", b"content");
+        assert_eq!(cache.get("a", b"pointer").unwrap(), b"content");
+
+        // Keys depend on the filter name and command
+        let other = SmudgeCache::open(td.path(), DEFAULT_MAX_SIZE, "lfs", "other smudge");
+        assert_eq!(other.get("a", b"pointer"), None);
+
+        // And on the path, if the driver sees it
+        let by_path =
+            SmudgeCache::open(td.path(), DEFAULT_MAX_SIZE, "lfs", "git-lfs smudge").keyed_by_path();
+        by_path.put("a", b"pointer", b"a content");
+        assert_eq!(by_path.get("b", b"pointer"), None);
+        assert_eq!(by_path.get("a", b"pointer").unwrap(), b"a content");
+
+        // Corrupt entries are dropped
+        let entry = cache.entry("a", b"pointer");
+        let mut data = fs::read(&entry).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&entry, data).unwrap();
+        assert_eq!(cache.get("a", b"pointer"), None);
+        assert!(!entry.exists());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let td = TempDir::new().unwrap();
+        let entry_size = (HASH_LEN + 100) as u64;
+        // Room for three entries; eviction leaves at most 90% of the limit
+        let cache = SmudgeCache::open(td.path(), entry_size * 3 + 60, "f", "cmd");
+        let age = |input: &[u8], secs: u64| {
+            let file = File::options()
+                .write(true)
+                .open(cache.entry("x", input))
+                .unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        for (i, input) in [b"a", b"b", b"c"].iter().enumerate() {
+            cache.put("x", *input, &[i as u8; 100]);
+            age(*input, i as u64 + 1);
+        }
+        // Reading `a` makes `b` the least recently used
+        assert!(cache.get("x", b"a").is_some());
+        cache.put("x", b"d", &[3; 100]);
+
+        assert!(cache.get("x", b"b").is_none());
+        for input in [b"a", b"c", b"d"] {
+            assert!(cache.get("x", input).is_some());
+        }
+        assert_eq!(
+            SmudgeCache::open(td.path(), 0, "f", "cmd")
+                .size
+                .into_inner(),
+            entry_size * 3
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Per-filter invocation statistics, read with
+//! [`ProcessFilterRegistration::stats`](crate::ProcessFilterRegistration::stats).
+//!
+//! Spawns, reuses and failure causes are noted where they happen (often in
+//! static helpers that do not know which filter they run for) in a
+//! thread-local, and collected by the filter invocation that caused them:
+//! filters run synchronously on the calling thread.
+
+use git2::FilterMode;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the [`LatencyHistogram`] buckets; the last bucket has no
+/// bound.
+const BUCKETS: [Duration; 6] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// Why a filter invocation failed or passed its content through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum FailureKind {
+    /// A command or long-running process could not be started.
+    Spawn,
+    /// A single-shot command did not exit in time and was killed.
+    Timeout,
+    /// A single-shot command exited with a non-zero status.
+    ExitStatus,
+    /// The long-running process died or broke the protocol.
+    Protocol,
+    /// The filter reported an error: `status=error` or `status=abort` from a
+    /// long-running process, or an error from an in-process filter.
+    Filter,
+    /// The content was passed through unfiltered, e.g. because the process
+    /// does not support the direction (an error if the filter is required).
+    Declined,
+    /// The output was rejected by `verifypointer` or `verifyroundtrip`.
+    Verification,
+    /// A [`FilterObserver`](crate::FilterObserver) vetoed the request.
+    Vetoed,
+    /// Any other error.
+    Other,
+}
+
+impl FailureKind {
+    /// Short snake_case name, e.g. `exit_status`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureKind::Spawn => "spawn",
+            FailureKind::Timeout => "timeout",
+            FailureKind::ExitStatus => "exit_status",
+            FailureKind::Protocol => "protocol",
+            FailureKind::Filter => "filter",
+            FailureKind::Declined => "declined",
+            FailureKind::Verification => "verification",
+            FailureKind::Vetoed => "vetoed",
+            FailureKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Distribution of invocation wall times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS.len() + 1],
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, elapsed: Duration) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+    }
+
+    /// Each bucket's upper bound (`None` for the last one) and the number of
+    /// invocations that took at most that long, but longer than the previous
+    /// bound. Bounds are 1 ms, 10 ms, 100 ms, 1 s, 10 s and 60 s.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        BUCKETS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// Statistics of one direction of a filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModeStats {
+    /// Blobs filtered, including failures and cache hits.
+    pub invocations: u64,
+    /// Invocations answered by `smudgecache` or `cleancache`.
+    pub cache_hits: u64,
+    /// Bytes given to the filter.
+    pub bytes_in: u64,
+    /// Bytes returned by successful invocations.
+    pub bytes_out: u64,
+    /// Total time spent in the filter.
+    pub wall_time: Duration,
+    /// Distribution of the time spent per invocation.
+    pub latency: LatencyHistogram,
+}
+
+/// Statistics of a registered filter since it was registered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Clean (worktree → ODB) invocations.
+    pub clean: ModeStats,
+    /// Smudge (ODB → worktree) invocations.
+    pub smudge: ModeStats,
+    /// Failed invocations by cause. Invocations that passed their content
+    /// through are counted as [`FailureKind::Declined`].
+    pub failures: BTreeMap<FailureKind, u64>,
+    /// Commands and long-running processes started, including restarts.
+    pub spawns: u64,
+    /// Requests sent to an already running long-running process.
+    pub reuses: u64,
+}
+
+impl FilterStats {
+    /// Failed invocations of `kind`.
+    pub fn failures(&self, kind: FailureKind) -> u64 {
+        self.failures.get(&kind).copied().unwrap_or(0)
+    }
+
+    /// Commands killed for not exiting in time.
+    pub fn timeouts(&self) -> u64 {
+        self.failures(FailureKind::Timeout)
+    }
+}
+
+/// What happened during one invocation, besides its result.
+#[derive(Default)]
+struct Notes {
+    failure: Option<FailureKind>,
+    declined: bool,
+    cache_hit: bool,
+    spawns: u64,
+    reuses: u64,
+}
+
+thread_local! {
+    static NOTES: RefCell<Notes> = RefCell::default();
+}
+
+fn note(f: impl FnOnce(&mut Notes)) {
+    NOTES.with(|notes| f(&mut notes.borrow_mut()));
+}
+
+/// Record why the current invocation fails. The first cause wins: later
+/// errors usually follow from it.
+pub(crate) fn note_failure(kind: FailureKind) {
+    note(|notes| {
+        notes.failure.get_or_insert(kind);
+    });
+}
+
+/// Record that the current invocation passes its content through.
+pub(crate) fn note_declined() {
+    note(|notes| notes.declined = true);
+}
+
+/// Whether the current invocation has passed its content through.
+pub(crate) fn declined() -> bool {
+    NOTES.with(|notes| notes.borrow().declined)
+}
+
+pub(crate) fn note_cache_hit() {
+    note(|notes| notes.cache_hit = true);
+}
+
+pub(crate) fn note_spawn() {
+    note(|notes| notes.spawns += 1);
+}
+
+pub(crate) fn note_reuse() {
+    note(|notes| notes.reuses += 1);
+}
+
+/// Accumulates the statistics of one registered filter.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    stats: Mutex<FilterStats>,
+}
+
+impl Recorder {
+    /// Start an invocation: forget notes left by work done outside of one.
+    pub(crate) fn begin() {
+        NOTES.with(|notes| notes.take());
+    }
+
+    /// Account for an invocation started with [`begin`](Self::begin).
+    pub(crate) fn record(
+        &self,
+        name: &str,
+        mode: FilterMode,
+        input: usize,
+        output: Option<usize>,
+        elapsed: Duration,
+    ) {
+        let notes = NOTES.with(|notes| notes.take());
+        let failure = match output {
+            Some(_) if notes.declined => Some(FailureKind::Declined),
+            Some(_) => None,
+            None => Some(notes.failure.unwrap_or(FailureKind::Other)),
+        };
+
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let mode_stats = match mode {
+            FilterMode::ToOdb => &mut stats.clean,
+            FilterMode::ToWorktree => &mut stats.smudge,
+        };
+        mode_stats.invocations += 1;
+        mode_stats.cache_hits += notes.cache_hit as u64;
+        mode_stats.bytes_in += input as u64;
+        mode_stats.bytes_out += output.unwrap_or(0) as u64;
+        mode_stats.wall_time += elapsed;
+        mode_stats.latency.record(elapsed);
+        if let Some(kind) = failure {
+            *stats.failures.entry(kind).or_default() += 1;
+        }
+        stats.spawns += notes.spawns;
+        stats.reuses += notes.reuses;
+        drop(stats);
+
+        #[cfg(feature = "metrics")]
+        export(name, mode, input, output, elapsed, failure, &notes);
+        #[cfg(not(feature = "metrics"))]
+        let _ = name;
+    }
+
+    pub(crate) fn snapshot(&self) -> FilterStats {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Report an invocation to the `metrics` recorder, labelled with the filter
+/// name and direction.
+#[cfg(feature = "metrics")]
+fn export(
+    name: &str,
+    mode: FilterMode,
+    input: usize,
+    output: Option<usize>,
+    elapsed: Duration,
+    failure: Option<FailureKind>,
+    notes: &Notes,
+) {
+    let filter = name.to_string();
+    let mode = match mode {
+        FilterMode::ToOdb => "clean",
+        FilterMode::ToWorktree => "smudge",
+    };
+    let labels = [("filter", filter.clone()), ("mode", mode.to_string())];
+    metrics::counter!("git2_process_filter_invocations_total", &labels).increment(1);
+    if notes.cache_hit {
+        metrics::counter!("git2_process_filter_cache_hits_total", &labels).increment(1);
+    }
+    metrics::counter!("git2_process_filter_bytes_in_total", &labels).increment(input as u64);
+    if let Some(output) = output {
+        metrics::counter!("git2_process_filter_bytes_out_total", &labels).increment(output as u64);
+    }
+    metrics::histogram!("git2_process_filter_duration_seconds", &labels)
+        .record(elapsed.as_secs_f64());
+    if let Some(kind) = failure {
+        metrics::counter!(
+            "git2_process_filter_failures_total",
+            "filter" => filter.clone(),
+            "mode" => mode,
+            "kind" => kind.as_str()
+        )
+        .increment(1);
+    }
+    if notes.spawns > 0 {
+        metrics::counter!("git2_process_filter_spawns_total", "filter" => filter.clone())
+            .increment(notes.spawns);
+    }
+    if notes.reuses > 0 {
+        metrics::counter!("git2_process_filter_reuses_total", "filter" => filter)
+            .increment(notes.reuses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let recorder = Recorder::default();
+        Recorder::begin();
+        note_spawn();
+        recorder.record(
+            "f",
+            FilterMode::ToOdb,
+            10,
+            Some(4),
+            Duration::from_millis(5),
+        );
+
+        // Notes from outside an invocation are dropped
+        note_spawn();
+        Recorder::begin();
+        note_failure(FailureKind::Timeout);
+        note_failure(FailureKind::ExitStatus);
+        recorder.record("f", FilterMode::ToOdb, 10, None, Duration::from_secs(2));
+
+        Recorder::begin();
+        note_reuse();
+        note_failure(FailureKind::Protocol);
+        note_declined();
+        recorder.record("f", FilterMode::ToWorktree, 3, Some(3), Duration::ZERO);
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.clean.invocations, 2);
+        assert_eq!(stats.clean.bytes_in, 20);
+        assert_eq!(stats.clean.bytes_out, 4);
+        assert_eq!(stats.clean.wall_time, Duration::from_millis(2005));
+        let buckets: Vec<u64> = stats.clean.latency.buckets().map(|(_, n)| n).collect();
+        assert_eq!(buckets, [0, 1, 0, 0, 1, 0, 0]);
+        assert_eq!(stats.smudge.invocations, 1);
+        assert_eq!(stats.timeouts(), 1);
+        assert_eq!(stats.failures(FailureKind::Declined), 1);
+        assert_eq!(stats.failures(FailureKind::ExitStatus), 0);
+        assert_eq!((stats.spawns, stats.reuses), (1, 1));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_export() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let debugging = DebuggingRecorder::new();
+        let snapshotter = debugging.snapshotter();
+        metrics::with_local_recorder(&debugging, || {
+            let recorder = Recorder::default();
+            Recorder::begin();
+            note_spawn();
+            note_cache_hit();
+            recorder.record(
+                "f",
+                FilterMode::ToOdb,
+                10,
+                Some(4),
+                Duration::from_millis(5),
+            );
+            Recorder::begin();
+            note_failure(FailureKind::Timeout);
+            recorder.record("f", FilterMode::ToWorktree, 3, None, Duration::from_secs(2));
+        });
+
+        let metrics: BTreeMap<(String, Vec<String>), DebugValue> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels = key
+                    .labels()
+                    .map(|l| format!("{}={}", l.key(), l.value()))
+                    .collect();
+                ((key.name().to_string(), labels), value)
+            })
+            .collect();
+        let get = |name: &str, labels: &[&str]| {
+            let labels = labels.iter().map(|l| l.to_string()).collect();
+            metrics.get(&(format!("git2_process_filter_{}", name), labels))
+        };
+        let counter = |name: &str, labels: &[&str]| match get(name, labels) {
+            Some(DebugValue::Counter(n)) => *n,
+            other => panic!("{} {:?}: {:?}", name, labels, other),
+        };
+
+        let clean = ["filter=f", "mode=clean"];
+        let smudge = ["filter=f", "mode=smudge"];
+        assert_eq!(counter("invocations_total", &clean), 1);
+        assert_eq!(counter("invocations_total", &smudge), 1);
+        assert_eq!(counter("cache_hits_total", &clean), 1);
+        assert_eq!(get("cache_hits_total", &smudge), None);
+        assert_eq!(counter("bytes_in_total", &clean), 10);
+        assert_eq!(counter("bytes_out_total", &clean), 4);
+        assert_eq!(get("bytes_out_total", &smudge), None);
+        assert_eq!(
+            counter(
+                "failures_total",
+                &["filter=f", "mode=smudge", "kind=timeout"]
+            ),
+            1
+        );
+        assert_eq!(counter("spawns_total", &["filter=f"]), 1);
+        match get("duration_seconds", &smudge) {
+            Some(DebugValue::Histogram(values)) => assert_eq!(values.len(), 1),
+            other => panic!("duration_seconds: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! `diff.<driver>.textconv` support for diffs made with git2.
+
+use crate::{trace, ProcessFilter};
+use git2::{
+    AttrCheckFlags, AttrValue, DiffDelta, DiffFile, Error, ObjectType, Oid, Repository, Signature,
+};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Mode of the blobs in a notes tree.
+const NOTE_MODE: i32 = 0o100644;
+
+/// Old and new content of a delta.
+type OldAndNew = (Vec<u8>, Vec<u8>);
+
+/// A `diff.<driver>` with a `textconv` command.
+struct TextconvDriver {
+    command: String,
+    /// Notes ref of the cache, if `diff.<driver>.cachetextconv` is set.
+    cache_ref: Option<String>,
+    /// Tree of the cache when it was loaded, if it is valid for `command`.
+    cache_tree: Option<Oid>,
+    /// Conversions made since the cache was last written.
+    pending: Vec<(Oid, Vec<u8>)>,
+}
+
+/// Runs `diff.<driver>.textconv` commands for files whose `diff` attribute
+/// names a driver, as git does when showing diffs.
+///
+/// Blobs are written to a temporary file whose path is appended to the
+/// command, which is parsed like a filter command (quotes, no shell syntax),
+/// and the command's stdout is used in place of the content. With
+/// `diff.<driver>.cachetextconv = true`, results are cached in the same
+/// `refs/notes/textconv/<driver>` notes git uses, so both share the cache. It
+/// is written by [`flush`](Self::flush), or when the `Textconv` is dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use git2::{Patch, Repository};
+/// use git2_process_filter::Textconv;
+///
+/// let repo = Repository::open(".")?;
+/// let head = repo.head()?.peel_to_tree()?;
+/// let diff = repo.diff_tree_to_workdir(Some(&head), None)?;
+///
+/// let mut textconv = Textconv::new(&repo);
+/// for delta in diff.deltas() {
+///     // .gitattributes: *.pdf diff=pdf; config: diff.pdf.textconv = exiftool
+///     if let Some((old, new)) = textconv.convert_delta(&delta)? {
+///         let mut patch = Patch::from_buffers(
+///             &old,
+///             delta.old_file().path(),
+///             &new,
+///             delta.new_file().path(),
+///             None,
+///         )?;
+///         print!("{}", patch.to_buf()?.as_str().unwrap_or(""));
+///     }
+/// }
+/// textconv.flush()?;
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct Textconv<'repo> {
+    repo: &'repo Repository,
+    /// Drivers by name, `None` for drivers without `textconv`.
+    drivers: HashMap<String, Option<TextconvDriver>>,
+}
+
+impl<'repo> Textconv<'repo> {
+    /// Run the textconv drivers configured in `repo`.
+    pub fn new(repo: &'repo Repository) -> Self {
+        Textconv {
+            repo,
+            drivers: HashMap::new(),
+        }
+    }
+
+    /// The textconv driver for `path`, loading it on first use.
+    fn driver(&mut self, path: &Path) -> Result<Option<&mut TextconvDriver>, Error> {
+        let value = self
+            .repo
+            .get_attr(path, "diff", AttrCheckFlags::FILE_THEN_INDEX)?;
+        let AttrValue::String(name) = AttrValue::from_string(value) else {
+            return Ok(None);
+        };
+        if !self.drivers.contains_key(name) {
+            let driver = self.load_driver(name)?;
+            self.drivers.insert(name.to_string(), driver);
+        }
+        Ok(self.drivers.get_mut(name).and_then(Option::as_mut))
+    }
+
+    fn load_driver(&self, name: &str) -> Result<Option<TextconvDriver>, Error> {
+        let config = self.repo.config()?;
+        let command = match config.get_string(&format!("diff.{}.textconv", name)) {
+            Ok(command) if !command.trim().is_empty() => command,
+            _ => return Ok(None),
+        };
+        let cache = config
+            .get_bool(&format!("diff.{}.cachetextconv", name))
+            .unwrap_or(false);
+        let cache_ref = cache.then(|| format!("refs/notes/textconv/{}", name));
+
+        // Like git, a cache made by another command is ignored
+        let cache_tree = cache_ref.as_deref().and_then(|cache_ref| {
+            let commit = self
+                .repo
+                .find_reference(cache_ref)
+                .and_then(|r| r.peel_to_commit())
+                .ok()?;
+            (commit.summary().map(str::trim) == Some(command.trim())).then(|| commit.tree_id())
+        });
+        Ok(Some(TextconvDriver {
+            command,
+            cache_ref,
+            cache_tree,
+            pending: Vec::new(),
+        }))
+    }
+
+    /// The converted content of the blob `id` at `path`, or `None` if `path`
+    /// has no textconv driver. A zero or unknown `id` reads the file from
+    /// the working tree, which is never cached.
+    pub fn convert(&mut self, path: &Path, id: Oid) -> Result<Option<Vec<u8>>, Error> {
+        let repo = self.repo;
+        let Some(driver) = self.driver(path)? else {
+            return Ok(None);
+        };
+
+        let blob = (!id.is_zero()).then(|| repo.find_blob(id).ok()).flatten();
+        if blob.is_some() {
+            if let Some(text) = driver.cached(repo, id) {
+                trace::event(|| format!("textconv cache hit for {} ({})", path.display(), id));
+                return Ok(Some(text));
+            }
+        }
+        let content = match &blob {
+            Some(blob) => blob.content().to_vec(),
+            None => {
+                let workdir = repo
+                    .workdir()
+                    .ok_or_else(|| Error::from_str(&format!("blob {} not found", id)))?;
+                std::fs::read(workdir.join(path)).map_err(|e| {
+                    Error::from_str(&format!("failed to read '{}': {}", path.display(), e))
+                })?
+            }
+        };
+
+        let text = run(&driver.command, path, repo.workdir(), &content)?;
+        if blob.is_some() && driver.cache_ref.is_some() {
+            driver.pending.push((id, text.clone()));
+        }
+        Ok(Some(text))
+    }
+
+    /// The converted old and new content of `delta`, or `None` if neither
+    /// side has a textconv driver. A side without a driver, or which does
+    /// not exist, is returned as is.
+    pub fn convert_delta(&mut self, delta: &DiffDelta<'_>) -> Result<Option<OldAndNew>, Error> {
+        let old = self.convert_file(&delta.old_file())?;
+        let new = self.convert_file(&delta.new_file())?;
+        if old.1 || new.1 {
+            Ok(Some((old.0, new.0)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The content of one side of a delta, and whether it was converted.
+    fn convert_file(&mut self, file: &DiffFile<'_>) -> Result<(Vec<u8>, bool), Error> {
+        let Some(path) = file.path().filter(|_| file.exists()) else {
+            return Ok((Vec::new(), false));
+        };
+        if let Some(text) = self.convert(path, file.id())? {
+            return Ok((text, true));
+        }
+        let content = match self.repo.find_blob(file.id()) {
+            Ok(blob) => blob.content().to_vec(),
+            Err(_) => self
+                .repo
+                .workdir()
+                .and_then(|workdir| std::fs::read(workdir.join(path)).ok())
+                .unwrap_or_default(),
+        };
+        Ok((content, false))
+    }
+
+    /// Write new results to the textconv caches.
+    ///
+    /// Each cache is a notes commit without parents whose message is the
+    /// textconv command, as git writes it.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for driver in self.drivers.values_mut().flatten() {
+            driver.write_cache(self.repo)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Textconv<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!(
+                "[git2-process-filter] warning: failed to write textconv cache: {}",
+                e.message()
+            );
+        }
+    }
+}
+
+impl TextconvDriver {
+    fn cached(&self, repo: &Repository, id: Oid) -> Option<Vec<u8>> {
+        if let Some((_, text)) = self.pending.iter().find(|(oid, _)| *oid == id) {
+            return Some(text.clone());
+        }
+        let tree = repo.find_tree(self.cache_tree?).ok()?;
+        let note = note_in_tree(repo, &tree, &id.to_string())?;
+        Some(note)
+    }
+
+    fn write_cache(&mut self, repo: &Repository) -> Result<(), Error> {
+        let Some(cache_ref) = &self.cache_ref else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let base = self.cache_tree.and_then(|tree| repo.find_tree(tree).ok());
+        let mut builder = repo.treebuilder(base.as_ref())?;
+        for (id, text) in &self.pending {
+            builder.insert(id.to_string(), repo.blob(text)?, NOTE_MODE)?;
+        }
+        let tree = repo.find_tree(builder.write()?)?;
+        let signature = repo
+            .signature()
+            .or_else(|_| Signature::now("git2-process-filter", "git2-process-filter@localhost"))?;
+        let commit = repo.commit(None, &signature, &signature, &self.command, &tree, &[])?;
+        repo.reference(cache_ref, commit, true, "textconv cache")?;
+        trace::event(|| {
+            format!(
+                "wrote {} textconv results to {}",
+                self.pending.len(),
+                cache_ref
+            )
+        });
+
+        self.cache_tree = Some(tree.id());
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Find the note for `hex` in a notes tree, which may fan out into
+/// subtrees named after leading pairs of hex digits.
+fn note_in_tree(repo: &Repository, tree: &git2::Tree<'_>, hex: &str) -> Option<Vec<u8>> {
+    for entry in tree.iter() {
+        let name = entry.name()?;
+        if name == hex && entry.kind() == Some(ObjectType::Blob) {
+            return Some(repo.find_blob(entry.id()).ok()?.content().to_vec());
+        }
+        if entry.kind() == Some(ObjectType::Tree) {
+            if let Some(rest) = hex.strip_prefix(name) {
+                let subtree = repo.find_tree(entry.id()).ok()?;
+                return note_in_tree(repo, &subtree, rest);
+            }
+        }
+    }
+    None
+}
+
+/// Run `command` on `content`, written to a temporary file named after `path`
+/// whose path is appended to the command, as git does.
+fn run(
+    command: &str,
+    path: &Path,
+    workdir: Option<&Path>,
+    content: &[u8],
+) -> Result<Vec<u8>, Error> {
+    // Keep the file name: converters often look at the extension. Like
+    // git's mkstemp, the file is private and created exclusively
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let write_error =
+        |e: std::io::Error| Error::from_str(&format!("failed to write blob for textconv: {}", e));
+    let mut tmp = tempfile::Builder::new()
+        .prefix("git2-textconv-")
+        .suffix(&format!("_{}", name))
+        .tempfile()
+        .map_err(write_error)?;
+    tmp.write_all(content).map_err(write_error)?;
+
+    let (program, mut args) = ProcessFilter::parse_command(command, "");
+    args.push(tmp.path().to_string_lossy().into_owned());
+    ProcessFilter::run_program(&program, &args, workdir, b"").map_err(|e| {
+        Error::from_str(&format!(
+            "textconv '{}' failed for '{}': {}",
+            command,
+            path.display(),
+            e.message()
+        ))
+    })
+}
@@ -0,0 +1,333 @@
+//! Opt-in trace of everything exchanged with filter processes, similar to
+//! `GIT_TRACE_PACKET`.
+//!
+//! Tracing is enabled with the `GIT2_PROCESS_FILTER_TRACE` environment variable
+//! (`1`/`true` for stderr, or an absolute path to append to a file) or with
+//! [`enable`]. It logs every pkt-line sent to and received from long-running
+//! filter processes, and every spawn and exit of single-shot filter commands
+//! with byte counts. Binary payloads are hexdumped and long text is truncated.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use git2_process_filter::trace::{self, TraceTarget};
+//!
+//! trace::enable(TraceTarget::File("/tmp/filter-trace.log".into()))?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable that enables tracing.
+pub const TRACE_ENV: &str = "GIT2_PROCESS_FILTER_TRACE";
+
+/// Number of payload bytes shown for binary packets and long text.
+const MAX_SHOWN: usize = 64;
+
+/// Where trace output goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceTarget {
+    /// Write to stderr.
+    Stderr,
+    /// Append to a file.
+    File(PathBuf),
+}
+
+impl TraceTarget {
+    /// Interpret a `GIT2_PROCESS_FILTER_TRACE` value the way git interprets
+    /// `GIT_TRACE_PACKET`: `1`, `2` or `true` mean stderr, an absolute path
+    /// means a file, anything else disables tracing.
+    pub fn from_env_value(value: &str) -> Option<TraceTarget> {
+        match value.to_ascii_lowercase().as_str() {
+            "" | "0" | "false" | "no" | "off" => None,
+            "1" | "2" | "true" | "yes" | "on" => Some(TraceTarget::Stderr),
+            _ if value.starts_with('/') => Some(TraceTarget::File(PathBuf::from(value))),
+            _ => None,
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+static FROM_ENV: Once = Once::new();
+
+/// Start tracing to `target`, replacing any previous target (including one
+/// set through the environment).
+pub fn enable(target: TraceTarget) -> io::Result<()> {
+    // Make sure a later first use does not override this with the environment
+    FROM_ENV.call_once(|| {});
+    set_sink(Some(open(target)?));
+    Ok(())
+}
+
+/// Stop tracing.
+pub fn disable() {
+    FROM_ENV.call_once(|| {});
+    set_sink(None);
+}
+
+fn open(target: TraceTarget) -> io::Result<Sink> {
+    Ok(match target {
+        TraceTarget::Stderr => Sink::Stderr,
+        TraceTarget::File(path) => {
+            Sink::File(OpenOptions::new().create(true).append(true).open(path)?)
+        }
+    })
+}
+
+fn set_sink(sink: Option<Sink>) {
+    let mut current = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    ENABLED.store(sink.is_some(), Ordering::Relaxed);
+    *current = sink;
+}
+
+/// Whether tracing is on. Reads the environment on first use.
+pub(crate) fn enabled() -> bool {
+    FROM_ENV.call_once(|| {
+        let target = std::env::var(TRACE_ENV)
+            .ok()
+            .and_then(|v| TraceTarget::from_env_value(&v));
+        if let Some(target) = target {
+            match open(target) {
+                Ok(sink) => set_sink(Some(sink)),
+                Err(e) => eprintln!(
+                    "[git2-process-filter] warning: cannot open {}: {}",
+                    TRACE_ENV, e
+                ),
+            }
+        }
+    });
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Trace a line if tracing is on. `message` is only built when needed.
+pub(crate) fn event(message: impl FnOnce() -> String) {
+    if enabled() {
+        write_line(&message());
+    }
+}
+
+fn write_line(message: &str) {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86400;
+    let line = format!(
+        "{:02}:{:02}:{:02}.{:06} git2-process-filter: {}\n",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros(),
+        message
+    );
+
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = match sink.as_mut() {
+        Some(Sink::Stderr) => io::stderr().write_all(line.as_bytes()),
+        Some(Sink::File(file)) => file.write_all(line.as_bytes()),
+        None => Ok(()),
+    };
+}
+
+/// Render a packet payload: text as-is (truncated), binary as a hexdump.
+fn render_payload(data: &[u8]) -> String {
+    let text = data.strip_suffix(b"\n").unwrap_or(data);
+    let printable = text
+        .iter()
+        .all(|&b| b == b'\t' || (0x20..0x7f).contains(&b));
+
+    if printable && text.len() <= MAX_SHOWN {
+        return String::from_utf8_lossy(text).into_owned();
+    }
+    if printable {
+        return format!(
+            "{}... ({} bytes)",
+            String::from_utf8_lossy(&text[..MAX_SHOWN]),
+            data.len()
+        );
+    }
+
+    let shown = &data[..data.len().min(MAX_SHOWN)];
+    let hex: Vec<String> = shown.iter().map(|b| format!("{:02x}", b)).collect();
+    let suffix = if data.len() > MAX_SHOWN { " ..." } else { "" };
+    format!("[{}{}] ({} bytes)", hex.join(" "), suffix, data.len())
+}
+
+/// Splits a raw byte stream back into pkt-lines and renders each one.
+pub(crate) struct PacketTracer {
+    /// Prefix such as `git-lfs>` (sent to the process) or `git-lfs<` (received).
+    prefix: String,
+    buf: Vec<u8>,
+    /// Set once the stream stops looking like pkt-lines.
+    broken: bool,
+}
+
+impl PacketTracer {
+    pub(crate) fn new(label: &str, direction: char) -> Self {
+        PacketTracer {
+            prefix: format!("{}{}", label, direction),
+            buf: Vec::new(),
+            broken: false,
+        }
+    }
+
+    /// Feed bytes from the stream, returning one rendered line per complete packet.
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.broken {
+            return lines;
+        }
+        self.buf.extend_from_slice(data);
+
+        while self.buf.len() >= 4 {
+            let len = std::str::from_utf8(&self.buf[..4])
+                .ok()
+                .and_then(|s| usize::from_str_radix(s, 16).ok());
+            match len {
+                Some(0) => {
+                    lines.push(format!("packet: {} 0000", self.prefix));
+                    self.buf.drain(..4);
+                }
+                Some(len) if len >= 4 => {
+                    if self.buf.len() < len {
+                        break;
+                    }
+                    lines.push(format!(
+                        "packet: {} {}",
+                        self.prefix,
+                        render_payload(&self.buf[4..len])
+                    ));
+                    self.buf.drain(..len);
+                }
+                _ => {
+                    lines.push(format!(
+                        "packet: {} invalid packet header {}",
+                        self.prefix,
+                        render_payload(&self.buf[..4])
+                    ));
+                    self.broken = true;
+                    self.buf.clear();
+                }
+            }
+        }
+        lines
+    }
+
+    fn trace(&mut self, data: &[u8]) {
+        for line in self.feed(data) {
+            write_line(&line);
+        }
+    }
+}
+
+/// A pipe to or from a long-running process that traces the pkt-lines passing
+/// through it. Without a tracer it is a plain passthrough.
+pub(crate) struct Traced<T> {
+    inner: T,
+    tracer: Option<PacketTracer>,
+}
+
+impl<T> Traced<T> {
+    /// Wrap `inner`, tracing only if tracing is currently enabled.
+    pub(crate) fn new(inner: T, label: &str, direction: char) -> Self {
+        Traced {
+            inner,
+            tracer: enabled().then(|| PacketTracer::new(label, direction)),
+        }
+    }
+}
+
+impl<R: Read> Read for Traced<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Traced<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_env_value() {
+        assert_eq!(TraceTarget::from_env_value(""), None);
+        assert_eq!(TraceTarget::from_env_value("0"), None);
+        assert_eq!(TraceTarget::from_env_value("1"), Some(TraceTarget::Stderr));
+        assert_eq!(
+            TraceTarget::from_env_value("true"),
+            Some(TraceTarget::Stderr)
+        );
+        assert_eq!(
+            TraceTarget::from_env_value("/tmp/trace.log"),
+            Some(TraceTarget::File(PathBuf::from("/tmp/trace.log")))
+        );
+        assert_eq!(TraceTarget::from_env_value("relative.log"), None);
+    }
+
+    #[test]
+    fn test_render_payload() {
+        assert_eq!(render_payload(b"command=smudge\n"), "command=smudge");
+        assert_eq!(render_payload(&[0x00, 0xff, 0x10]), "[00 ff 10] (3 bytes)");
+        let long = vec![b'a'; 100];
+        assert_eq!(
+            render_payload(&long),
+            format!("{}... (100 bytes)", "a".repeat(MAX_SHOWN))
+        );
+        let binary = vec![0u8; 100];
+        assert!(render_payload(&binary).ends_with(" ...] (100 bytes)"));
+    }
+
+    #[test]
+    fn test_packet_tracer_split_input() {
+        let mut tracer = PacketTracer::new("lfs", '>');
+        let stream = b"0016git-filter-client\n000eversion=2\n0000";
+        let mut lines = Vec::new();
+        // Feed in awkward chunks, as pipes may deliver them
+        for chunk in stream.chunks(5) {
+            lines.extend(tracer.feed(chunk));
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "packet: lfs> git-filter-client",
+                "packet: lfs> version=2",
+                "packet: lfs> 0000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packet_tracer_invalid_stream() {
+        let mut tracer = PacketTracer::new("lfs", '<');
+        let lines = tracer.feed(b"hello world");
+        assert_eq!(lines, vec!["packet: lfs< invalid packet header hell"]);
+        assert!(tracer.feed(b"0000").is_empty());
+    }
+}
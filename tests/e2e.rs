@@ -1,12 +1,26 @@
 //! End-to-end tests comparing process filter output with git CLI.
 
-use git2::{FilterFlags, FilterList, FilterMode, Repository};
-use git2_process_filter::register_process_filter;
+use git2::{FilterFlags, FilterList, FilterMode, Oid, Repository};
+use git2_process_filter::lfs::{Pointer, TransferAgent};
+use git2_process_filter::trace::{self, TraceTarget};
+use git2_process_filter::{
+    register_in_process_filter, register_process_filter, register_process_filter_with_commands,
+    FailureKind, FilterChain, FilterEvent, FilterObserver, FilterRequest, MergeDriver,
+    RequestMetadata, Textconv,
+};
+#[cfg(feature = "http")]
+use lfs_server::LfsServer;
 use std::fs::{self, File};
 use std::io::Write;
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 
+#[cfg(feature = "http")]
+mod lfs_server;
+
 fn repo_init() -> (TempDir, Repository) {
     let td = TempDir::new().unwrap();
     let repo = Repository::init(td.path()).unwrap();
@@ -275,6 +289,8 @@ fn test_process_filter_lfs() {
         pointer_str.contains("size 2048"),
         "Pointer should have correct size"
     );
+    let parsed = Pointer::parse_canonical(pointer).unwrap();
+    assert_eq!(parsed, Pointer::from_content(&content));
 
     // Now test SMUDGE filter - convert pointer back to content
     let filter_list = FilterList::load(
@@ -292,6 +308,70 @@ fn test_process_filter_lfs() {
         content.as_slice(),
         "Smudge filter should restore original content"
     );
+    drop(filter_list);
+    drop(_reg);
+
+    // The built-in LFS filter must write the same pointer as git-lfs
+    repo.config()
+        .unwrap()
+        .set_str("filter.lfs.builtin", "lfs")
+        .unwrap();
+    let _reg = register_process_filter(&repo, "lfs").unwrap();
+    assert_eq!(
+        apply_filter(&repo, "test.bin", FilterMode::ToOdb, &content),
+        pointer
+    );
+}
+
+/// Test the built-in LFS clean: the object is stored in `lfs.storage` and
+/// replaced with a pointer, without running git-lfs.
+#[test]
+fn test_builtin_lfs_clean() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("lfsnative_{}", std::process::id());
+    {
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("filter.{}.builtin", filter_name), "lfs")
+            .unwrap();
+        // Would fail if used: the builtin takes precedence
+        config
+            .set_str(
+                &format!("filter.{}.process", filter_name),
+                "git-lfs-not-installed filter-process",
+            )
+            .unwrap();
+        config
+            .set_bool(&format!("filter.{}.required", filter_name), true)
+            .unwrap();
+        config.set_str("lfs.storage", "lfs-store").unwrap();
+    }
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.bin filter={}\n", filter_name),
+    )
+    .unwrap();
+    let content: Vec<u8> = (0..100_000).map(|i| (i % 256) as u8).collect();
+    fs::write(td.path().join("test.bin"), &content).unwrap();
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    let pointer = apply_filter(&repo, "test.bin", FilterMode::ToOdb, &content);
+
+    let oid = "db8f1d69251d95e2c88268d3c540533cc5182e0e33065a6f3f322f606a574489";
+    assert_eq!(
+        String::from_utf8(pointer).unwrap(),
+        format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 100000\n",
+            oid
+        )
+    );
+    let object = repo
+        .path()
+        .join("lfs-store/objects")
+        .join(&oid[0..2])
+        .join(&oid[2..4])
+        .join(oid);
+    assert_eq!(fs::read(object).unwrap(), content);
 }
 
 /// Test filter with empty commands (passthrough)
@@ -320,3 +400,1655 @@ fn test_process_filter_empty_commands() {
     // Should pass through unchanged
     assert_eq!(output.as_ref(), input);
 }
+
+/// Command line for the long-running test filter in `tests/fixtures`,
+/// or `None` if perl is not installed.
+fn filter_process_cmd(args: &str) -> Option<String> {
+    let perl_check = Command::new("perl").arg("-v").output();
+    if perl_check.is_err() || !perl_check.unwrap().status.success() {
+        eprintln!("Skipping test: perl not installed");
+        return None;
+    }
+    let script = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/filter-process.pl"
+    );
+    Some(format!("perl {} {}", script, args))
+}
+
+/// Configure `filter.<name>.process` and a `*.txt filter=<name>` attribute.
+fn setup_process_filter(td: &TempDir, repo: &Repository, name: &str, process_cmd: &str) {
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(&format!("filter.{}.process", name), process_cmd)
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", name),
+    )
+    .unwrap();
+}
+
+fn apply_filter(repo: &Repository, path: &str, mode: FilterMode, input: &[u8]) -> Vec<u8> {
+    try_apply_filter(repo, path, mode, input).unwrap()
+}
+
+fn try_apply_filter(
+    repo: &Repository,
+    path: &str,
+    mode: FilterMode,
+    input: &[u8],
+) -> Result<Vec<u8>, git2::Error> {
+    let filter_list = FilterList::load(repo, path, mode, FilterFlags::DEFAULT)
+        .unwrap()
+        .expect("Should have filter list");
+    filter_list
+        .apply_to_buffer(input)
+        .map(|buf| buf.as_ref().to_vec())
+}
+
+/// Test that `filter.<name>.process` starts one process and reuses it for
+/// every blob in both directions.
+#[test]
+fn test_process_filter_long_running() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={}", log.display())) else {
+        return;
+    };
+    let filter_name = format!("longrun_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+    assert_eq!(
+        apply_filter(&repo, "b.txt", FilterMode::ToWorktree, b"WORLD\n"),
+        b"world\n"
+    );
+    let large: Vec<u8> = b"abc".iter().cycle().take(200_000).copied().collect();
+    assert_eq!(
+        apply_filter(&repo, "c.txt", FilterMode::ToOdb, &large),
+        large.to_ascii_uppercase()
+    );
+
+    let blob = git2::Oid::hash_object(git2::ObjectType::Blob, b"WORLD\n").unwrap();
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        vec![
+            "start".to_string(),
+            "clean a.txt".to_string(),
+            format!("smudge b.txt blob={}", blob),
+            "clean c.txt".to_string(),
+        ]
+    );
+}
+
+/// Test that a process that dies mid-request is restarted and the blob retried.
+#[test]
+fn test_process_filter_restarts_crashed_process() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let marker = td.path().join("crashed");
+    let Some(cmd) = filter_process_cmd(&format!(
+        "--log={} --crash-once={}",
+        log.display(),
+        marker.display()
+    )) else {
+        return;
+    };
+    let filter_name = format!("restart_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        vec!["start", "clean a.txt", "start", "clean a.txt"]
+    );
+}
+
+/// Test that registrations count invocations, bytes, spawns vs reuses and
+/// failures by kind.
+#[test]
+fn test_filter_stats() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let marker = td.path().join("crashed");
+    let Some(cmd) = filter_process_cmd(&format!(
+        "--log={} --crash-once={}",
+        log.display(),
+        marker.display()
+    )) else {
+        return;
+    };
+    let filter_name = format!("stats_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+    apply_filter(&repo, "b.txt", FilterMode::ToOdb, b"world\n");
+    apply_filter(&repo, "c.txt", FilterMode::ToWorktree, b"ABC\n");
+
+    let stats = reg.stats();
+    assert_eq!(stats.clean.invocations, 2);
+    assert_eq!((stats.clean.bytes_in, stats.clean.bytes_out), (12, 12));
+    assert_eq!(stats.smudge.invocations, 1);
+    assert_eq!(
+        stats.clean.latency.buckets().map(|(_, n)| n).sum::<u64>(),
+        2
+    );
+    assert!(stats.clean.wall_time > Duration::ZERO);
+    // The crash restarted the process, and the retry succeeded
+    assert_eq!((stats.spawns, stats.reuses), (2, 2));
+    assert!(stats.failures.is_empty(), "{:?}", stats.failures);
+    drop(reg);
+
+    let commands_name = format!("statscmd_{}", std::process::id());
+    let reg =
+        register_process_filter_with_commands(&commands_name, "tr a-z A-Z", "git2-no-such-command")
+            .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", commands_name),
+    )
+    .unwrap();
+    apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+    assert!(try_apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n").is_err());
+    let stats = reg.stats();
+    assert_eq!((stats.spawns, stats.reuses), (1, 0));
+    assert_eq!(stats.failures(FailureKind::Spawn), 1);
+    assert_eq!(stats.smudge.bytes_out, 0);
+}
+
+/// Records every invocation, vetoes `secret.*` and rewrites `*.crlf`.
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl FilterObserver for Recorder {
+    fn before(&self, request: &mut FilterRequest<'_>) -> Result<(), git2::Error> {
+        if request.path().starts_with("secret.") {
+            return Err(git2::Error::from_str("secrets are not allowed"));
+        }
+        if request.path().ends_with(".crlf") {
+            let input = request.input().to_vec();
+            request.set_input(input.into_iter().filter(|&b| b != b'\r').collect());
+        }
+        Ok(())
+    }
+
+    fn after(&self, event: &FilterEvent<'_>) {
+        self.0.lock().unwrap().push(format!(
+            "{} {:?} {} {} {:?} {}->{:?} {}",
+            event.filter.split('_').next().unwrap(),
+            event.mode,
+            event.path,
+            event.driver,
+            event.command,
+            event.input_size,
+            event.output_size,
+            match event.result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e.message().to_string(),
+            }
+        ));
+    }
+}
+
+/// Test that observers see every invocation and can veto or rewrite it.
+#[test]
+fn test_filter_observer() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("observed_{}", std::process::id());
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("* filter={}\n", filter_name),
+    )
+    .unwrap();
+    let reg = register_process_filter_with_commands(&filter_name, "tr a-z A-Z", "").unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    reg.add_observer(Recorder(Arc::clone(&events)));
+
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+    assert_eq!(
+        apply_filter(&repo, "b.crlf", FilterMode::ToOdb, b"a\r\nb\r\n"),
+        b"A\nB\n"
+    );
+    let err = try_apply_filter(&repo, "secret.key", FilterMode::ToOdb, b"key").unwrap_err();
+    assert_eq!(err.message(), "secrets are not allowed");
+    apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n");
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "observed ToOdb a.txt commands Some(\"tr a-z A-Z\") 6->Some(6) ok",
+            "observed ToOdb b.crlf commands Some(\"tr a-z A-Z\") 4->Some(4) ok",
+            "observed ToOdb secret.key commands Some(\"tr a-z A-Z\") 3->None secrets are not allowed",
+            "observed ToWorktree a.txt commands None 6->Some(6) ok",
+        ]
+    );
+    assert_eq!(reg.stats().failures(FailureKind::Vetoed), 1);
+}
+
+/// Test that a blob that keeps crashing the process is passed through, or
+/// fails when `filter.<name>.required` is set.
+#[test]
+fn test_process_filter_crash_policy() {
+    let (td, repo) = repo_init();
+    let Some(cmd) = filter_process_cmd("--crash-on=bad.txt") else {
+        return;
+    };
+    let filter_name = format!("crashpolicy_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    {
+        let _reg = register_process_filter(&repo, &filter_name).unwrap();
+        assert_eq!(
+            apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+        // The process is restarted for the next blob
+        assert_eq!(
+            apply_filter(&repo, "good.txt", FilterMode::ToOdb, b"hello\n"),
+            b"HELLO\n"
+        );
+    }
+
+    repo.config()
+        .unwrap()
+        .set_bool(&format!("filter.{}.required", filter_name), true)
+        .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert!(try_apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n").is_err());
+}
+
+/// Test that `shutdown` stops the process cleanly and returns its stderr.
+#[test]
+fn test_process_filter_shutdown_report() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={} --stderr=filter-ready", log.display()))
+    else {
+        return;
+    };
+    let filter_name = format!("shutdown_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+
+    let report = reg.shutdown();
+    assert!(report.success(), "{:?}", report);
+    assert!(report.status.is_some());
+    assert_eq!(report.stderr, b"filter-ready\n");
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        vec!["start", "clean a.txt", "exit"]
+    );
+}
+
+/// Test that dropping the registration closes the process's stdin so it exits.
+#[test]
+fn test_process_filter_drop_stops_process() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={}", log.display())) else {
+        return;
+    };
+    let filter_name = format!("dropstop_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    {
+        let _reg = register_process_filter(&repo, &filter_name).unwrap();
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+    }
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(log.lines().last(), Some("exit"));
+}
+
+/// Test that smudge requests carry `ref=`, `treeish=` and `blob=` and that
+/// extra metadata is sent with every request.
+#[test]
+fn test_process_filter_request_metadata() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={}", log.display())) else {
+        return;
+    };
+    let filter_name = format!("metadata_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    let treeish = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+    reg.set_metadata(RequestMetadata {
+        ref_name: Some("refs/heads/main".to_string()),
+        treeish: Some(treeish),
+        extra: vec![("x-job".to_string(), "42".to_string())],
+    })
+    .unwrap();
+
+    apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n");
+    apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+
+    let blob = git2::Oid::hash_object(git2::ObjectType::Blob, b"HELLO\n").unwrap();
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        vec![
+            "start".to_string(),
+            format!(
+                "smudge a.txt blob={} ref=refs/heads/main treeish={} x-job=42",
+                blob, treeish
+            ),
+            "clean a.txt x-job=42".to_string(),
+        ]
+    );
+}
+
+/// Test that `status=error` declines only the blob it was sent for, which then
+/// passes through, or fails when `filter.<name>.required` is set.
+#[test]
+fn test_process_filter_status_error() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={} --error-on=bad.txt", log.display()))
+    else {
+        return;
+    };
+    let filter_name = format!("statuserror_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    {
+        let _reg = register_process_filter(&repo, &filter_name).unwrap();
+        // Not required: the failed blob passes through
+        assert_eq!(
+            apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+        assert_eq!(
+            apply_filter(&repo, "good.txt", FilterMode::ToOdb, b"hello\n"),
+            b"HELLO\n"
+        );
+        assert_eq!(
+            apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+    }
+
+    let lines = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        lines.lines().collect::<Vec<_>>(),
+        vec![
+            "start",
+            "clean bad.txt",
+            "clean good.txt",
+            "clean bad.txt",
+            "exit"
+        ]
+    );
+
+    repo.config()
+        .unwrap()
+        .set_bool(&format!("filter.{}.required", filter_name), true)
+        .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert!(try_apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n").is_err());
+    assert_eq!(
+        apply_filter(&repo, "good.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+}
+
+/// Test that `status=abort` stops all further requests for that command,
+/// which then follow the `required` policy without reaching the process.
+#[test]
+fn test_process_filter_status_abort() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={} --abort-on=bad.txt", log.display()))
+    else {
+        return;
+    };
+    let filter_name = format!("statusabort_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    {
+        let _reg = register_process_filter(&repo, &filter_name).unwrap();
+        // Not required: the aborted blob passes through, and so do further
+        // cleans, without reaching the process
+        assert_eq!(
+            apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+        assert_eq!(
+            apply_filter(&repo, "good.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+        // Smudge was not aborted
+        assert_eq!(
+            apply_filter(&repo, "good.txt", FilterMode::ToWorktree, b"HELLO\n"),
+            b"hello\n"
+        );
+    }
+
+    let blob = git2::Oid::hash_object(git2::ObjectType::Blob, b"HELLO\n").unwrap();
+    let lines = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        lines.lines().collect::<Vec<_>>(),
+        vec![
+            "start".to_string(),
+            "clean bad.txt".to_string(),
+            format!("smudge good.txt blob={}", blob),
+            "exit".to_string(),
+        ]
+    );
+
+    repo.config()
+        .unwrap()
+        .set_bool(&format!("filter.{}.required", filter_name), true)
+        .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert!(try_apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"hello\n").is_err());
+    assert!(try_apply_filter(&repo, "good.txt", FilterMode::ToOdb, b"hello\n").is_err());
+}
+
+/// Test that the capabilities advertised in the handshake can be queried and
+/// that a direction the process does not offer never reaches it.
+#[test]
+fn test_process_filter_capabilities() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!(
+        "--log={} --capabilities=smudge,delay,x-resume",
+        log.display()
+    )) else {
+        return;
+    };
+    let filter_name = format!("caps_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    let caps = reg.process_capabilities().unwrap().unwrap();
+    assert_eq!(caps.version, 2);
+    assert_eq!(caps.capabilities, vec!["smudge", "delay", "x-resume"]);
+    assert!(!caps.clean());
+    assert!(caps.smudge());
+    assert!(caps.delay());
+    assert_eq!(caps.unknown().collect::<Vec<_>>(), vec!["x-resume"]);
+
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+        b"hello\n"
+    );
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(log.lines().collect::<Vec<_>>(), vec!["start"]);
+
+    let (_td, repo) = repo_init();
+    let reg = register_process_filter(&repo, &format!("nocaps_{}", std::process::id())).unwrap();
+    assert!(reg.process_capabilities().unwrap().is_none());
+}
+
+/// Configure `filter.<name>.process` together with a `clean` command and
+/// check that a clean the process cannot serve runs the command instead.
+fn assert_falls_back_to_clean(repo: &Repository, name: &str, file: &str) {
+    repo.config()
+        .unwrap()
+        .set_str(&format!("filter.{}.clean", name), "tr a-z A-Z")
+        .unwrap();
+
+    let _reg = register_process_filter(repo, name).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            apply_filter(repo, file, FilterMode::ToOdb, b"hello\n"),
+            b"HELLO\n"
+        );
+    }
+}
+
+/// Test that a process without the clean capability falls back to
+/// `filter.<name>.clean`.
+#[test]
+fn test_process_filter_precedence_unsupported_capability() {
+    let (td, repo) = repo_init();
+    let Some(cmd) = filter_process_cmd("--capabilities=smudge") else {
+        return;
+    };
+    let filter_name = format!("precedencecap_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+
+    assert_falls_back_to_clean(&repo, &filter_name, "a.txt");
+    // The process still smudges
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n"),
+        b"hello\n"
+    );
+}
+
+/// Test that a process that fails to start falls back to
+/// `filter.<name>.clean`.
+#[test]
+fn test_process_filter_precedence_spawn_failure() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("precedencespawn_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, "/nonexistent/filter-process");
+
+    assert_falls_back_to_clean(&repo, &filter_name, "a.txt");
+    // Without a `smudge` command there is nothing to fall back to
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n"),
+        b"HELLO\n"
+    );
+}
+
+/// Test that the protocol trace records pkt-lines of long-running filters
+/// and spawns of single-shot filters.
+#[test]
+fn test_process_filter_trace() {
+    let (td, repo) = repo_init();
+    let Some(cmd) = filter_process_cmd("") else {
+        return;
+    };
+    let trace_file = td.path().join("trace.log");
+    trace::enable(TraceTarget::File(trace_file.clone())).unwrap();
+
+    let filter_name = format!("trace_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+    {
+        let _reg = register_process_filter(&repo, &filter_name).unwrap();
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+    }
+
+    let shot_name = format!("traceshot_{}", std::process::id());
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.dat filter={}\n", shot_name),
+    )
+    .unwrap();
+    {
+        let _reg = register_process_filter_with_commands(&shot_name, "tr a-z A-Z", "").unwrap();
+        apply_filter(&repo, "b.dat", FilterMode::ToOdb, b"hello\n");
+    }
+    trace::disable();
+
+    let trace = fs::read_to_string(&trace_file).unwrap();
+    for expected in [
+        "packet: perl> git-filter-client",
+        "packet: perl< git-filter-server",
+        "packet: perl> command=clean",
+        "packet: perl> pathname=a.txt",
+        "packet: perl> hello",
+        "packet: perl< status=success",
+        "packet: perl< HELLO",
+        "packet: perl< 0000",
+        "' exited with exit status: 0",
+        "spawned \"tr\"",
+        "'tr' wrote 6 bytes, read 6 bytes",
+    ] {
+        assert!(
+            trace.contains(expected),
+            "missing {:?} in trace:\n{}",
+            expected,
+            trace
+        );
+    }
+}
+
+/// Test that a Rust closure registered in-process filters like an external
+/// command registered under the same name would, including `required`.
+#[test]
+fn test_in_process_filter_matches_command() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("inproc_{}", std::process::id());
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", filter_name),
+    )
+    .unwrap();
+
+    let external = {
+        let _reg = register_process_filter_with_commands(&filter_name, "tr a-z A-Z", "").unwrap();
+        apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello world\n")
+    };
+
+    let _reg = register_in_process_filter(
+        &filter_name,
+        false,
+        |mode: FilterMode, _path: &str, input: &[u8]| match mode {
+            FilterMode::ToOdb => Ok(input.to_ascii_uppercase()),
+            FilterMode::ToWorktree => Ok(input.to_vec()),
+        },
+    )
+    .unwrap();
+    let in_process = apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello world\n");
+
+    assert_eq!(in_process, external);
+    assert_eq!(in_process, b"HELLO WORLD\n");
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"HELLO\n"),
+        b"HELLO\n"
+    );
+    drop(_reg);
+
+    let failing = |_: FilterMode, _: &str, _: &[u8]| Err(git2::Error::from_str("broken"));
+    {
+        let _reg = register_in_process_filter(&filter_name, false, failing).unwrap();
+        assert_eq!(
+            apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n"),
+            b"hello\n"
+        );
+    }
+    let _reg = register_in_process_filter(&filter_name, true, failing).unwrap();
+    let err = try_apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n").unwrap_err();
+    assert!(err.message().contains("broken"), "{}", err.message());
+}
+
+/// Test a chain mixing a long-running process from config, a command and a
+/// Rust stage, and that a failing stage is named in the error.
+#[test]
+fn test_filter_chain() {
+    let (td, repo) = repo_init();
+    let log = td.path().join("filter.log");
+    let Some(cmd) = filter_process_cmd(&format!("--log={}", log.display())) else {
+        return;
+    };
+    let stage_name = format!("chainstage_{}", std::process::id());
+    let chain_name = format!("chain_{}", std::process::id());
+    repo.config()
+        .unwrap()
+        .set_str(&format!("filter.{}.process", stage_name), &cmd)
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", chain_name),
+    )
+    .unwrap();
+
+    let reg = FilterChain::new(&chain_name)
+        .config_stage(&repo, &stage_name)
+        .unwrap()
+        .command_stage("rot13", "tr A-Za-z N-ZA-Mn-za-m", "tr A-Za-z N-ZA-Mn-za-m")
+        .in_process_stage("check", |_mode: FilterMode, path: &str, input: &[u8]| {
+            if path == "bad.txt" {
+                return Err(git2::Error::from_str("rejected"));
+            }
+            Ok(input.to_vec())
+        })
+        .register()
+        .unwrap();
+
+    // clean: upper, rot13, check; smudge: check, rot13, lower
+    let clean = apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello\n");
+    assert_eq!(clean, b"URYYB\n");
+    assert_eq!(
+        apply_filter(&repo, "a.txt", FilterMode::ToWorktree, &clean),
+        b"hello\n"
+    );
+
+    let err = try_apply_filter(&repo, "bad.txt", FilterMode::ToOdb, b"x\n").unwrap_err();
+    assert!(
+        err.message().contains(&format!(
+            "filter '{}' stage 3/3 'check' failed to clean: rejected",
+            chain_name
+        )),
+        "{}",
+        err.message()
+    );
+
+    assert!(reg.process_capabilities().unwrap().unwrap().clean());
+    assert!(reg.shutdown().success());
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(log.lines().next(), Some("start"));
+    assert_eq!(log.lines().last(), Some("exit"));
+}
+
+/// Test the built-in LFS smudge on a pre-seeded object store, and the
+/// `filter.<name>.missing` policy for objects that are not there.
+#[test]
+fn test_builtin_lfs_smudge() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("lfssmudge_{}", std::process::id());
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(&format!("filter.{}.builtin", filter_name), "lfs")
+        .unwrap();
+    config
+        .set_str(&format!("filter.{}.missing", filter_name), "keep")
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.bin filter={}\n", filter_name),
+    )
+    .unwrap();
+
+    // Seed the store the way a CI cache would: just the object file
+    let oid = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    let objects = repo.path().join("lfs/objects/58/91");
+    fs::create_dir_all(&objects).unwrap();
+    fs::write(objects.join(oid), b"hello\n").unwrap();
+    let pointer = format!(
+        "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 6\n",
+        oid
+    );
+    let missing = format!(
+        "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 6\n",
+        "0".repeat(64)
+    );
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert_eq!(
+        apply_filter(&repo, "a.bin", FilterMode::ToWorktree, pointer.as_bytes()),
+        b"hello\n"
+    );
+    assert_eq!(
+        apply_filter(&repo, "b.bin", FilterMode::ToWorktree, missing.as_bytes()),
+        missing.as_bytes()
+    );
+    drop(reg);
+
+    config
+        .set_str(&format!("filter.{}.missing", filter_name), "fail")
+        .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    let err =
+        try_apply_filter(&repo, "b.bin", FilterMode::ToWorktree, missing.as_bytes()).unwrap_err();
+    assert!(
+        err.message().contains("not available locally"),
+        "{}",
+        err.message()
+    );
+}
+
+/// Test that the built-in LFS smudge downloads missing objects from the LFS
+/// server with the batch API when `filter.<name>.missing = fetch`.
+#[cfg(feature = "http")]
+#[test]
+fn test_builtin_lfs_fetch() {
+    let server = LfsServer::start();
+    let content: Vec<u8> = (0..300_000).map(|i| (i % 253) as u8).collect();
+    let pointer = server.add(&content).to_string();
+    let unknown = Pointer::from_content(b"not on the server\n").to_string();
+
+    let (td, repo) = repo_init();
+    let filter_name = format!("lfsfetch_{}", std::process::id());
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(&format!("filter.{}.builtin", filter_name), "lfs")
+        .unwrap();
+    config
+        .set_str(&format!("filter.{}.missing", filter_name), "fetch")
+        .unwrap();
+    config.set_str("lfs.url", &server.url()).unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.bin filter={}\n", filter_name),
+    )
+    .unwrap();
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            apply_filter(&repo, "a.bin", FilterMode::ToWorktree, pointer.as_bytes()),
+            content
+        );
+    }
+    // The first smudge stored the object, so the second did not download it
+    let oid = Pointer::parse(pointer.as_bytes()).unwrap().oid;
+    assert_eq!(
+        server.requests(),
+        vec![
+            "POST /repo.git/info/lfs/objects/batch".to_string(),
+            format!("GET /download/{}", oid),
+        ]
+    );
+
+    let err =
+        try_apply_filter(&repo, "b.bin", FilterMode::ToWorktree, unknown.as_bytes()).unwrap_err();
+    assert!(
+        err.message().contains("404 Object does not exist"),
+        "{}",
+        err.message()
+    );
+}
+
+/// Test that `filter.<name>.missing = fetch` downloads the objects of LFS
+/// pointers a long-running process smudges to.
+#[cfg(feature = "http")]
+#[test]
+fn test_process_smudge_fetches_missing_objects() {
+    let server = LfsServer::start();
+    let content = b"large file content\n".to_vec();
+    let pointer = server.add(&content).to_string();
+
+    let (td, repo) = repo_init();
+    // Smudge lowercases, which leaves pointers as they are
+    let Some(cmd) = filter_process_cmd("") else {
+        return;
+    };
+    let filter_name = format!("processfetch_{}", std::process::id());
+    setup_process_filter(&td, &repo, &filter_name, &cmd);
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(&format!("filter.{}.missing", filter_name), "fetch")
+        .unwrap();
+    config.set_str("lfs.url", &server.url()).unwrap();
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            apply_filter(&repo, "a.txt", FilterMode::ToWorktree, pointer.as_bytes()),
+            content
+        );
+    }
+    // Stored by the first smudge
+    assert_eq!(server.requests().len(), 2);
+    // Other output is left alone, and clean is unaffected
+    assert_eq!(
+        apply_filter(&repo, "b.txt", FilterMode::ToWorktree, b"HELLO\n"),
+        b"hello\n"
+    );
+    assert_eq!(
+        apply_filter(&repo, "b.txt", FilterMode::ToOdb, b"hello\n"),
+        b"HELLO\n"
+    );
+}
+
+/// Test uploading and fetching LFS objects through a standalone custom
+/// transfer agent, and that an error it reports reaches the caller.
+#[test]
+fn test_lfs_transfer_agent() {
+    if filter_process_cmd("").is_none() {
+        return;
+    }
+    let script = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/transfer-agent.pl"
+    );
+    let (td, repo) = repo_init();
+    let share = td.path().join("share");
+    fs::create_dir(&share).unwrap();
+    let log = td.path().join("agent.log");
+
+    let filter_name = format!("lfsagent_{}", std::process::id());
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(&format!("filter.{}.builtin", filter_name), "lfs")
+        .unwrap();
+    config
+        .set_str(&format!("filter.{}.missing", filter_name), "fetch")
+        .unwrap();
+    config
+        .set_str("lfs.customtransfer.nfs.path", "perl")
+        .unwrap();
+    config
+        .set_str(
+            "lfs.customtransfer.nfs.args",
+            &format!(
+                "{} --dir={} --log={}",
+                script,
+                share.display(),
+                log.display()
+            ),
+        )
+        .unwrap();
+    config
+        .set_str("lfs.standalonetransferagent", "nfs")
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.bin filter={}\n", filter_name),
+    )
+    .unwrap();
+
+    // Put an object on the share through the agent
+    let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let pointer = Pointer::from_content(&content);
+    let upload = td.path().join("upload.tmp");
+    fs::write(&upload, &content).unwrap();
+    let agent = TransferAgent::standalone(&repo, None).unwrap().unwrap();
+    agent.upload(&pointer.oid, pointer.size, &upload).unwrap();
+    assert!(agent.shutdown().success());
+    assert_eq!(fs::read(share.join(&pointer.oid)).unwrap(), content);
+
+    // Smudge fetches it through a download agent; the second is served locally
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    let text = pointer.to_string();
+    for _ in 0..2 {
+        assert_eq!(
+            apply_filter(&repo, "a.bin", FilterMode::ToWorktree, text.as_bytes()),
+            content
+        );
+    }
+    let unknown = Pointer::from_content(b"not on the share\n").to_string();
+    let err =
+        try_apply_filter(&repo, "b.bin", FilterMode::ToWorktree, unknown.as_bytes()).unwrap_err();
+    assert!(
+        err.message()
+            .contains("transfer failed: 404 not on the share"),
+        "{}",
+        err.message()
+    );
+    drop(reg);
+
+    let unknown_oid = Pointer::parse(unknown.as_bytes()).unwrap().oid;
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        format!(
+            "init upload origin\nupload {oid}\nterminate\n\
+             init download origin\ndownload {oid}\ndownload {unknown_oid}\nterminate\n",
+            oid = pointer.oid
+        )
+    );
+}
+
+/// Test that `verifypointer` rejects clean output that is not a pointer to a
+/// stored object, and leaves smudges and empty files alone.
+#[test]
+fn test_verify_clean_pointer() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("lfsverify_{}", std::process::id());
+    let mut config = repo.config().unwrap();
+    // A misconfigured LFS clean that passes content straight through
+    config
+        .set_str(&format!("filter.{}.clean", filter_name), "cat")
+        .unwrap();
+    config
+        .set_bool(&format!("filter.{}.verifypointer", filter_name), true)
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.bin filter={}\n", filter_name),
+    )
+    .unwrap();
+    let content: Vec<u8> = (0..100_000).map(|i| (i % 256) as u8).collect();
+    let pointer = Pointer::from_content(&content).to_string();
+
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    let err = try_apply_filter(&repo, "a.bin", FilterMode::ToOdb, &content).unwrap_err();
+    assert!(
+        err.message()
+            .contains("did not clean 'a.bin' into an LFS pointer"),
+        "{}",
+        err.message()
+    );
+    let err = try_apply_filter(&repo, "a.bin", FilterMode::ToOdb, pointer.as_bytes()).unwrap_err();
+    assert!(
+        err.message().contains("which is not in the local store"),
+        "{}",
+        err.message()
+    );
+    // Only clean output is checked, and empty files are not pointers
+    assert_eq!(
+        apply_filter(&repo, "a.bin", FilterMode::ToWorktree, &content),
+        content
+    );
+    assert_eq!(apply_filter(&repo, "e.bin", FilterMode::ToOdb, b""), b"");
+    drop(reg);
+
+    config
+        .set_str(&format!("filter.{}.builtin", filter_name), "lfs")
+        .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    assert_eq!(
+        apply_filter(&repo, "a.bin", FilterMode::ToOdb, &content),
+        pointer.as_bytes()
+    );
+}
+
+/// Test that `smudgecache` serves repeated smudges of the same content without
+/// running the command, and never caches cleans.
+#[test]
+fn test_smudge_cache() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("smudgecache_{}", std::process::id());
+    let log = td.path().join("runs.log");
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(
+            &format!("filter.{}.smudge", filter_name),
+            &format!("sh -c \"echo run >> {}; tr a-z A-Z\"", log.display()),
+        )
+        .unwrap();
+    config
+        .set_str(
+            &format!("filter.{}.smudgecache", filter_name),
+            "smudge-cache",
+        )
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", filter_name),
+    )
+    .unwrap();
+    let runs = || fs::read_to_string(&log).unwrap_or_default().lines().count();
+
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+    for path in ["a.txt", "b.txt"] {
+        assert_eq!(
+            apply_filter(&repo, path, FilterMode::ToWorktree, b"hello"),
+            b"HELLO"
+        );
+    }
+    // The second smudge of the same content was served from the cache
+    assert_eq!(runs(), 1);
+    assert!(repo.commondir().join("smudge-cache").is_dir());
+
+    apply_filter(&repo, "a.txt", FilterMode::ToWorktree, b"other");
+    assert_eq!(runs(), 2);
+    // Clean is never cached
+    apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello");
+    apply_filter(&repo, "a.txt", FilterMode::ToOdb, b"hello");
+    assert_eq!(runs(), 2);
+}
+
+/// Test that `cleancache` skips the clean command for unchanged files, is
+/// invalidated by stat changes, and can be bypassed through the registration.
+#[test]
+fn test_clean_cache() {
+    let (td, repo) = repo_init();
+    let filter_name = format!("cleancache_{}", std::process::id());
+    let log = td.path().join("runs.log");
+    let mut config = repo.config().unwrap();
+    config
+        .set_str(
+            &format!("filter.{}.clean", filter_name),
+            &format!("sh -c \"echo run >> {}; tr A-Z a-z\"", log.display()),
+        )
+        .unwrap();
+    config
+        .set_bool(&format!("filter.{}.cleancache", filter_name), true)
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", filter_name),
+    )
+    .unwrap();
+    let runs = || fs::read_to_string(&log).unwrap_or_default().lines().count();
+    let write = |content: &[u8], age: u64| {
+        let file = td.path().join("a.txt");
+        fs::write(&file, content).unwrap();
+        let modified = std::time::SystemTime::now() - Duration::from_secs(age);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    };
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+    let clean = |content: &[u8]| apply_filter(&repo, "a.txt", FilterMode::ToOdb, content);
+
+    write(b"HELLO", 60);
+    assert_eq!(clean(b"HELLO"), b"hello");
+    assert_eq!(clean(b"HELLO"), b"hello");
+    assert_eq!(runs(), 1);
+    assert!(repo.path().join("clean-cache").is_dir());
+
+    // Same size, different modification time
+    write(b"WORLD", 30);
+    assert_eq!(clean(b"WORLD"), b"world");
+    assert_eq!(runs(), 2);
+    // Content that is not the file's is never served from the cache
+    assert_eq!(clean(b"OTHER"), b"other");
+    assert_eq!(runs(), 3);
+    // Just modified: racily clean, so not cached
+    write(b"AGAIN", 0);
+    clean(b"AGAIN");
+    clean(b"AGAIN");
+    assert_eq!(runs(), 5);
+
+    write(b"HELLO", 60);
+    clean(b"HELLO");
+    reg.bypass_clean_cache(true);
+    clean(b"HELLO");
+    reg.bypass_clean_cache(false);
+    clean(b"HELLO");
+    assert_eq!(runs(), 7);
+}
+
+/// Test that `verifyroundtrip` reports lossy and non-idempotent cleans with a
+/// diff summary, and checks only what a clean-only or smudge-only filter can.
+#[test]
+fn test_verify_roundtrip() {
+    let (td, repo) = repo_init();
+    let mut config = repo.config().unwrap();
+    let mut attributes = String::new();
+    // (extension, clean, smudge)
+    let drivers = [
+        (
+            "ident",
+            "sed \"s/[$]Id[^$]*[$]/$Id$/\"",
+            "sed \"s/[$]Id[$]/$Id: v1 $/\"",
+        ),
+        ("quote", "sed \"s/^/> /\"", "sed \"s/^> //\""),
+        ("lower", "tr A-Z a-z", ""),
+        ("upper", "", "tr a-z A-Z"),
+    ];
+    for (ext, clean, smudge) in drivers {
+        let name = format!("roundtrip_{}_{}", ext, std::process::id());
+        config
+            .set_str(&format!("filter.{}.clean", name), clean)
+            .unwrap();
+        config
+            .set_str(&format!("filter.{}.smudge", name), smudge)
+            .unwrap();
+        config
+            .set_bool(&format!("filter.{}.verifyroundtrip", name), true)
+            .unwrap();
+        attributes.push_str(&format!("*.{} filter={}\n", ext, name));
+    }
+    fs::write(td.path().join(".gitattributes"), attributes).unwrap();
+    let _regs: Vec<_> = drivers
+        .iter()
+        .map(|(ext, _, _)| {
+            register_process_filter(&repo, &format!("roundtrip_{}_{}", ext, std::process::id()))
+                .unwrap()
+        })
+        .collect();
+
+    assert_eq!(
+        apply_filter(&repo, "a.ident", FilterMode::ToOdb, b"$Id: v1 $\n"),
+        b"$Id$\n"
+    );
+    // A keyword the smudge filter does not restore
+    let err = try_apply_filter(&repo, "b.ident", FilterMode::ToOdb, b"x\n$Id: v0 $\n").unwrap_err();
+    assert!(
+        err.message().contains("does not round-trip 'b.ident'")
+            && err
+                .message()
+                .contains("first difference at byte 8 (line 2)"),
+        "{}",
+        err.message()
+    );
+
+    let err = try_apply_filter(&repo, "a.quote", FilterMode::ToOdb, b"text\n").unwrap_err();
+    assert!(
+        err.message()
+            .contains("clean is not idempotent for 'a.quote'")
+            && err
+                .message()
+                .contains("expected \"> text\\n\", got \"> > text\\n\""),
+        "{}",
+        err.message()
+    );
+
+    // Without smudge, only idempotence is checked
+    assert_eq!(
+        apply_filter(&repo, "a.lower", FilterMode::ToOdb, b"TEXT\n"),
+        b"text\n"
+    );
+    // Without clean, nothing is: smudging raw content would not round-trip
+    assert_eq!(
+        apply_filter(&repo, "a.upper", FilterMode::ToOdb, b"text\n"),
+        b"text\n"
+    );
+}
+
+/// A git-crypt key file (format 2) holding one key.
+fn git_crypt_key_file(aes: &[u8; 32], hmac: &[u8; 64]) -> Vec<u8> {
+    let mut data = b"\0GITCRYPTKEY".to_vec();
+    data.extend_from_slice(&2u32.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    for (id, value) in [(1u32, &0u32.to_be_bytes()[..]), (3, aes), (5, hmac)] {
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        data.extend_from_slice(value);
+    }
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Run `openssl` with `input` on stdin.
+fn openssl(args: &[&str], input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new("openssl")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+/// The built-in git-crypt filter writes what git-crypt's scheme gives when
+/// computed step by step with openssl, and decrypts it again.
+#[test]
+fn test_builtin_git_crypt() {
+    let openssl_check = Command::new("openssl").arg("version").output();
+    if openssl_check.is_err() || !openssl_check.unwrap().status.success() {
+        eprintln!("Skipping test: openssl not installed");
+        return;
+    }
+
+    let (td, repo) = repo_init();
+    let aes: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let hmac: [u8; 64] = std::array::from_fn(|i| 0x80 + i as u8);
+    let keys = repo.path().join("git-crypt/keys");
+    fs::create_dir_all(&keys).unwrap();
+    fs::write(keys.join("default"), git_crypt_key_file(&aes, &hmac)).unwrap();
+
+    let filter_name = format!("gitcrypt_{}", std::process::id());
+    repo.config()
+        .unwrap()
+        .set_str(&format!("filter.{}.builtin", filter_name), "git-crypt")
+        .unwrap();
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("secret/** filter={}\n", filter_name),
+    )
+    .unwrap();
+    let _reg = register_process_filter(&repo, &filter_name).unwrap();
+
+    let content: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+    let encrypted = apply_filter(&repo, "secret/key.pem", FilterMode::ToOdb, &content);
+
+    // nonce = HMAC-SHA1(content)[..12]; AES-256-CTR with IV = nonce || 0u32
+    let mac = openssl(
+        &[
+            "dgst",
+            "-sha1",
+            "-binary",
+            "-mac",
+            "HMAC",
+            "-macopt",
+            &format!("hexkey:{}", hex(&hmac)),
+        ],
+        &content,
+    );
+    let nonce = &mac[..12];
+    let ciphertext = openssl(
+        &[
+            "enc",
+            "-aes-256-ctr",
+            "-K",
+            &hex(&aes),
+            "-iv",
+            &format!("{}00000000", hex(nonce)),
+        ],
+        &content,
+    );
+    let mut expected = b"\0GITCRYPT\0".to_vec();
+    expected.extend_from_slice(nonce);
+    expected.extend_from_slice(&ciphertext);
+    assert_eq!(encrypted, expected);
+
+    assert_eq!(
+        apply_filter(&repo, "secret/key.pem", FilterMode::ToWorktree, &encrypted),
+        content
+    );
+    let mut tampered = encrypted;
+    tampered[100] ^= 0x01;
+    let err =
+        try_apply_filter(&repo, "secret/key.pem", FilterMode::ToWorktree, &tampered).unwrap_err();
+    assert!(err.message().contains("HMAC check failed"), "{}", err);
+}
+
+/// `working-tree-encoding` converts like git in both directions, with git's
+/// BOM and round-trip checks.
+#[test]
+fn test_working_tree_encoding_matches_git() {
+    let (td, repo) = repo_init();
+    fs::write(
+        td.path().join(".gitattributes"),
+        "*.rc working-tree-encoding=UTF-16\n\
+         *.le working-tree-encoding=utf-16le\n\
+         *.sjis working-tree-encoding=SHIFT-JIS\n",
+    )
+    .unwrap();
+    let text = "caf\u{e9} \u{65e5}\u{672c} \u{1f600}\r\n";
+    let sjis_text = "\u{65e5}\u{672c}\u{8a9e}\n";
+    let utf16le: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    // What git writes for UTF-16: a BOM and native byte order
+    let utf16: Vec<u8> = "\u{feff}"
+        .encode_utf16()
+        .chain(text.encode_utf16())
+        .flat_map(u16::to_ne_bytes)
+        .collect();
+    let sjis = b"\x93\xfa\x96\x7b\x8c\xea\n".to_vec();
+    let files = [
+        ("res.rc", &utf16, text),
+        ("res.le", &utf16le, text),
+        ("ja.sjis", &sjis, sjis_text),
+    ];
+    for (file, content, _) in files {
+        fs::write(td.path().join(file), content).unwrap();
+    }
+
+    let output = Command::new("git")
+        .args(["add", "res.rc", "res.le", "ja.sjis"])
+        .current_dir(td.path())
+        .output()
+        .expect("git add failed");
+    assert!(output.status.success(), "git add failed: {:?}", output);
+
+    let _reg = git2_process_filter::register_working_tree_encoding(&repo).unwrap();
+    for (file, content, utf8) in files {
+        // Also check what git checks out
+        fs::remove_file(td.path().join(file)).unwrap();
+        let output = Command::new("git")
+            .args(["checkout", "--", file])
+            .current_dir(td.path())
+            .output()
+            .expect("git checkout failed");
+        assert!(output.status.success(), "git checkout failed: {:?}", output);
+        assert_eq!(&fs::read(td.path().join(file)).unwrap(), content);
+
+        let output = Command::new("git")
+            .args(["show", &format!(":{}", file)])
+            .current_dir(td.path())
+            .output()
+            .expect("git show failed");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, utf8.as_bytes(), "git stored {}", file);
+
+        assert_eq!(
+            apply_filter(&repo, file, FilterMode::ToOdb, content),
+            utf8.as_bytes(),
+            "clean {}",
+            file
+        );
+        assert_eq!(
+            &apply_filter(&repo, file, FilterMode::ToWorktree, utf8.as_bytes()),
+            content,
+            "smudge {}",
+            file
+        );
+    }
+
+    // BOM required / prohibited
+    let err = try_apply_filter(&repo, "res.rc", FilterMode::ToOdb, &utf16[2..]).unwrap_err();
+    assert!(err.message().contains("BOM is required"), "{}", err);
+    let mut bom_le = vec![0xff, 0xfe];
+    bom_le.extend(&utf16le);
+    let err = try_apply_filter(&repo, "res.le", FilterMode::ToOdb, &bom_le).unwrap_err();
+    assert!(err.message().contains("BOM is prohibited"), "{}", err);
+
+    // 0xEEEF is a duplicate of 0xFA40 (U+2170), which is what encoding writes
+    let err = try_apply_filter(&repo, "x.sjis", FilterMode::ToOdb, b"\xee\xef").unwrap_err();
+    assert!(
+        err.message().contains("to UTF-8 and back is not the same"),
+        "{}",
+        err
+    );
+    // Files without the attribute are not filtered
+    assert!(
+        FilterList::load(&repo, "notes.txt", FilterMode::ToOdb, FilterFlags::DEFAULT)
+            .unwrap()
+            .is_none()
+    );
+}
+
+/// Checking out a blob that cannot be encoded leaves it in UTF-8, like git,
+/// while adding such a file fails.
+#[test]
+fn test_working_tree_encoding_unencodable_checkout() {
+    let (td, repo) = repo_init();
+    fs::write(
+        td.path().join(".gitattributes"),
+        "*.sjis working-tree-encoding=SHIFT-JIS\n\
+         *.bad working-tree-encoding=NO-SUCH-ENCODING\n",
+    )
+    .unwrap();
+    // SHIFT-JIS has no emoji
+    let utf8 = "smile \u{1f600}\n".as_bytes();
+    let oid = repo.blob(utf8).unwrap().to_string();
+
+    let _reg = git2_process_filter::register_working_tree_encoding(&repo).unwrap();
+    for file in ["x.sjis", "x.bad"] {
+        let output = Command::new("git")
+            .args(["update-index", "--add", "--cacheinfo"])
+            .arg(format!("100644,{},{}", oid, file))
+            .current_dir(td.path())
+            .output()
+            .expect("git update-index failed");
+        assert!(output.status.success(), "{:?}", output);
+        let output = Command::new("git")
+            .args(["checkout", "--", file])
+            .current_dir(td.path())
+            .output()
+            .expect("git checkout failed");
+        assert!(output.status.success(), "git checkout failed: {:?}", output);
+        let git_checked_out = fs::read(td.path().join(file)).unwrap();
+        assert_eq!(git_checked_out, utf8);
+
+        assert_eq!(
+            apply_filter(&repo, file, FilterMode::ToWorktree, utf8),
+            git_checked_out,
+            "smudge {}",
+            file
+        );
+    }
+    let err = try_apply_filter(&repo, "x.bad", FilterMode::ToOdb, utf8).unwrap_err();
+    assert!(
+        err.message().contains("unsupported working-tree-encoding"),
+        "{}",
+        err
+    );
+}
+
+/// Run a git command in `dir`, returning its stdout.
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("git failed");
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {:?}",
+        args,
+        output
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Textconv runs `diff.<driver>.textconv` and shares its
+/// `refs/notes/textconv/<driver>` cache with git.
+#[test]
+fn test_textconv_cache_shared_with_git() {
+    let (td, repo) = repo_init();
+    let dir = td.path();
+    let log = dir.join("conv.log");
+    let script = dir.join("conv.sh");
+    fs::write(
+        &script,
+        format!("echo \"$1\" >> '{}'\nod -An -tx1 \"$1\"\n", log.display()),
+    )
+    .unwrap();
+    let command = format!("sh {}", script.display());
+    let mut config = repo.config().unwrap();
+    config.set_str("diff.hex.textconv", &command).unwrap();
+    config.set_bool("diff.hex.cachetextconv", true).unwrap();
+    fs::write(dir.join(".gitattributes"), "*.hex diff=hex\n").unwrap();
+
+    let mut commits = Vec::new();
+    for content in ["one\n", "two\n", "three\n"] {
+        fs::write(dir.join("a.hex"), content).unwrap();
+        git(dir, &["add", "."]);
+        git(dir, &["commit", "-q", "-m", content.trim()]);
+        commits.push(git(dir, &["rev-parse", "HEAD"]).trim().to_string());
+    }
+    let blob = |commit: &str| git(dir, &["rev-parse", &format!("{}:a.hex", commit)]);
+    let runs = || fs::read_to_string(&log).unwrap_or_default().lines().count();
+    let convert = |from: &str, to: &str| {
+        let tree = |c: &str| {
+            repo.find_commit(Oid::from_str(c).unwrap())
+                .unwrap()
+                .tree()
+                .unwrap()
+        };
+        let diff = repo
+            .diff_tree_to_tree(Some(&tree(from)), Some(&tree(to)), None)
+            .unwrap();
+        let mut textconv = Textconv::new(&repo);
+        let delta = diff.deltas().next().unwrap();
+        textconv.convert_delta(&delta).unwrap().unwrap()
+    };
+
+    // git fills the cache and Textconv reads it
+    let git_diff = git(dir, &["diff", &commits[0], &commits[1]]);
+    assert!(git_diff.contains("- 6f 6e 65 0a"), "{}", git_diff);
+    assert_eq!(runs(), 2);
+    let (old, new) = convert(&commits[0], &commits[1]);
+    assert_eq!(runs(), 2);
+    assert_eq!(old, b" 6f 6e 65 0a\n");
+    assert_eq!(new, b" 74 77 6f 0a\n");
+
+    // Textconv fills the cache and git reads it
+    let (_, new) = convert(&commits[1], &commits[2]);
+    assert_eq!(runs(), 3);
+    assert_eq!(
+        git(
+            dir,
+            &[
+                "notes",
+                "--ref=textconv/hex",
+                "show",
+                blob(&commits[2]).trim()
+            ]
+        ),
+        String::from_utf8(new).unwrap()
+    );
+    let git_diff = git(dir, &["diff", &commits[1], &commits[2]]);
+    assert!(git_diff.contains("+ 74 68 72 65 65 0a"), "{}", git_diff);
+    assert_eq!(runs(), 3);
+
+    // A different command invalidates the cache; working tree files are
+    // converted but not cached
+    config.set_str("diff.hex.textconv", "sh ./conv.sh").unwrap();
+    fs::write(dir.join("a.hex"), "four\n").unwrap();
+    let head = repo.head().unwrap().peel_to_tree().unwrap();
+    let diff = repo.diff_tree_to_workdir(Some(&head), None).unwrap();
+    let mut textconv = Textconv::new(&repo);
+    let (old, new) = textconv
+        .convert_delta(&diff.deltas().next().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!((old.len(), new.len()), (19, 16));
+    assert_eq!(runs(), 5);
+    drop(textconv);
+    assert_eq!(
+        git(
+            dir,
+            &["log", "-1", "--format=%s", "refs/notes/textconv/hex"]
+        )
+        .trim(),
+        "sh ./conv.sh"
+    );
+}
+
+/// MergeDriver runs `merge.<driver>.driver` for git2 merge conflicts and
+/// produces what `git merge` does.
+#[test]
+fn test_merge_driver_matches_git() {
+    let (td, repo) = repo_init();
+    let dir = td.path();
+    let mut config = repo.config().unwrap();
+    config
+        .set_str("merge.lockfile.driver", "sort -u %A %B -o %A")
+        .unwrap();
+    config
+        .set_str("merge.fail.driver", "sh -c \"echo %L %P > %A; exit 1\"")
+        .unwrap();
+    fs::write(
+        dir.join(".gitattributes"),
+        "*.lock merge=lockfile\n*.cfg merge=fail conflict-marker-size=10\n",
+    )
+    .unwrap();
+
+    let commit = |message: &str, files: &[(&str, &str)]| {
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        git(dir, &["add", "."]);
+        git(dir, &["commit", "-q", "-m", message]);
+        Oid::from_str(git(dir, &["rev-parse", "HEAD"]).trim()).unwrap()
+    };
+    commit("base", &[("deps.lock", "a\nm\nz\n"), ("app.cfg", "x=1\n")]);
+    git(dir, &["checkout", "-q", "-b", "other"]);
+    let theirs = commit(
+        "theirs",
+        &[("deps.lock", "a\nc\nz\n"), ("app.cfg", "x=3\n")],
+    );
+    git(dir, &["checkout", "-q", "-"]);
+    let ours = commit("ours", &[("deps.lock", "a\nb\nz\n"), ("app.cfg", "x=2\n")]);
+
+    // libgit2 ignores the drivers and reports both files as conflicts
+    let index = repo
+        .merge_commits(
+            &repo.find_commit(ours).unwrap(),
+            &repo.find_commit(theirs).unwrap(),
+            None,
+        )
+        .unwrap();
+    let mut results = std::collections::BTreeMap::new();
+    for conflict in index.conflicts().unwrap() {
+        let conflict = conflict.unwrap();
+        let path = String::from_utf8(conflict.our.as_ref().unwrap().path.clone()).unwrap();
+        let result = MergeDriver::resolve_conflict(&repo, &conflict)
+            .unwrap()
+            .unwrap();
+        results.insert(path, result);
+    }
+
+    let output = Command::new("git")
+        .args(["merge", "-q", "--no-edit", "other"])
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "app.cfg should conflict");
+    let status = git(dir, &["status", "--porcelain"]);
+    assert!(status.contains("UU app.cfg"), "{}", status);
+    assert!(status.contains("M  deps.lock"), "{}", status);
+
+    assert_eq!(results.len(), 2);
+    for (path, result) in &results {
+        assert_eq!(
+            result.content,
+            fs::read(dir.join(path)).unwrap(),
+            "{} differs from git",
+            path
+        );
+    }
+    assert_eq!(results["deps.lock"].content, b"a\nb\nc\nz\n");
+    assert!(!results["deps.lock"].conflict);
+    assert_eq!(results["app.cfg"].content, b"10 app.cfg\n");
+    assert!(results["app.cfg"].conflict);
+
+    // No driver for other files, or for git's built-in ones
+    assert!(MergeDriver::for_path(&repo, Path::new("README"))
+        .unwrap()
+        .is_none());
+    fs::write(dir.join(".gitattributes"), "*.lock merge=union\n").unwrap();
+    assert!(MergeDriver::for_path(&repo, Path::new("deps.lock"))
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,116 @@
+#!/usr/bin/perl
+#
+# Long-running filter used by the e2e tests. Speaks git's filter process
+# protocol, uppercasing content on clean and lowercasing it on smudge.
+#
+# Options:
+#   --log=FILE           append one line per event (including request metadata) to FILE
+#   --stderr=TEXT        print TEXT to stderr after the handshake
+#   --crash-on=PATH      exit without answering requests for PATH
+#   --error-on=PATH      answer requests for PATH with status=error
+#   --abort-on=PATH      answer requests for PATH with status=abort
+#   --crash-once=FILE    exit on the first request only (FILE marks that it happened)
+#   --capabilities=LIST  comma-separated capabilities to advertise (default: clean,smudge)
+
+use strict;
+use warnings;
+
+my %opt = (capabilities => 'clean,smudge');
+for (@ARGV) {
+    /^--([a-z-]+)=(.*)$/ or die "bad option: $_\n";
+    $opt{$1} = $2;
+}
+
+binmode STDIN;
+binmode STDOUT;
+$| = 1;
+
+sub log_event {
+    return unless $opt{log};
+    open my $fh, '>>', $opt{log} or die "cannot open log: $!\n";
+    print $fh join(' ', @_), "\n";
+    close $fh;
+}
+
+sub packet_read {
+    my $n = read(STDIN, my $len, 4);
+    if (!$n) {    # EOF: the client closed our stdin
+        log_event('exit');
+        exit 0;
+    }
+    die "short read\n" if $n != 4;
+    return undef if $len eq '0000';
+    my $size = hex($len) - 4;
+    my $buf = '';
+    while (length($buf) < $size) {
+        read(STDIN, $buf, $size - length($buf), length($buf)) or die "short read\n";
+    }
+    return $buf;
+}
+
+sub packet_list {
+    my @lines;
+    while (defined(my $p = packet_read())) {
+        chomp $p;
+        push @lines, $p;
+    }
+    return @lines;
+}
+
+sub packet_write {
+    my ($data) = @_;
+    printf STDOUT "%04x", length($data) + 4;
+    print STDOUT $data;
+}
+
+sub packet_flush {
+    print STDOUT "0000";
+}
+
+sub packet_texts {
+    packet_write("$_\n") for @_;
+    packet_flush();
+}
+
+my @welcome = packet_list();
+die "bad welcome\n" unless $welcome[0] eq 'git-filter-client' && grep { $_ eq 'version=2' } @welcome;
+packet_texts('git-filter-server', 'version=2');
+
+packet_list();    # capabilities offered by the client
+log_event('start');
+packet_texts(map { "capability=$_" } split /,/, $opt{capabilities});
+print STDERR "$opt{stderr}\n" if defined $opt{stderr};
+
+while (1) {
+    my %request = map { split /=/, $_, 2 } packet_list();
+    my $content = '';
+    while (defined(my $p = packet_read())) {
+        $content .= $p;
+    }
+    my @metadata = map { "$_=$request{$_}" } sort grep { !/^(command|pathname)$/ } keys %request;
+    log_event($request{command}, $request{pathname}, @metadata);
+
+    exit 1 if defined $opt{'crash-on'} && $request{pathname} eq $opt{'crash-on'};
+    if (defined $opt{'crash-once'} && !-e $opt{'crash-once'}) {
+        open my $fh, '>', $opt{'crash-once'} or die;
+        close $fh;
+        exit 1;
+    }
+
+    if (defined $opt{'error-on'} && $request{pathname} eq $opt{'error-on'}) {
+        packet_texts('status=error');
+        next;
+    }
+    if (defined $opt{'abort-on'} && $request{pathname} eq $opt{'abort-on'}) {
+        packet_texts('status=abort');
+        next;
+    }
+
+    my $output = $request{command} eq 'clean' ? uc $content : lc $content;
+    packet_texts('status=success');
+    for (my $i = 0; $i < length $output; $i += 65516) {
+        packet_write(substr $output, $i, 65516);
+    }
+    packet_flush();
+    packet_flush();    # empty trailing status list keeps "success"
+}
@@ -0,0 +1,63 @@
+#!/usr/bin/perl
+#
+# Standalone git-lfs custom transfer agent used by the e2e tests. Stores
+# objects as plain files in a directory, like an agent for an NFS share.
+#
+# Options:
+#   --dir=DIR     where objects are stored (required)
+#   --log=FILE    append one line per event to FILE
+
+use strict;
+use warnings;
+use File::Copy qw(copy);
+use JSON::PP;
+
+my %opt;
+for (@ARGV) {
+    /^--([a-z-]+)=(.*)$/ or die "bad option: $_\n";
+    $opt{$1} = $2;
+}
+die "--dir is required\n" unless $opt{dir};
+
+$| = 1;
+my $json = JSON::PP->new->canonical;
+
+sub log_event {
+    return unless $opt{log};
+    open my $fh, '>>', $opt{log} or die "cannot open log: $!\n";
+    print $fh join(' ', @_), "\n";
+    close $fh;
+}
+
+sub reply { print $json->encode($_[0]), "\n" }
+
+while (my $line = <STDIN>) {
+    my $msg = $json->decode($line);
+    my $event = $msg->{event};
+
+    if ($event eq 'init') {
+        log_event('init', $msg->{operation}, $msg->{remote});
+        reply({});
+    } elsif ($event eq 'download') {
+        log_event('download', $msg->{oid});
+        my $src = "$opt{dir}/$msg->{oid}";
+        if (!-e $src) {
+            reply({ event => 'complete', oid => $msg->{oid},
+                    error => { code => 404, message => 'not on the share' } });
+            next;
+        }
+        my $tmp = "$opt{dir}/.download-$$-$msg->{oid}";
+        copy($src, $tmp) or die "copy failed: $!\n";
+        reply({ event => 'progress', oid => $msg->{oid},
+                bytesSoFar => -s $tmp, bytesSinceLast => -s $tmp });
+        reply({ event => 'complete', oid => $msg->{oid}, path => $tmp });
+    } elsif ($event eq 'upload') {
+        log_event('upload', $msg->{oid});
+        copy($msg->{path}, "$opt{dir}/$msg->{oid}") or die "copy failed: $!\n";
+        reply({ event => 'complete', oid => $msg->{oid} });
+    } elsif ($event eq 'terminate') {
+        log_event('terminate');
+        exit 0;
+    }
+}
+log_event('eof');
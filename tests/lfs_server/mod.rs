@@ -0,0 +1,140 @@
+//! Minimal LFS server for offline tests: the batch API (download only, basic
+//! transfer) plus object downloads, served from memory.
+
+use git2_process_filter::lfs::Pointer;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tiny_http::{Header, Method, Response, Server};
+
+/// Token the server hands out in download actions and then requires.
+const TOKEN: &str = "Bearer test-download-token";
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<String, Vec<u8>>,
+    /// `METHOD path` of every request, in order.
+    requests: Vec<String>,
+}
+
+pub struct LfsServer {
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    thread: Option<JoinHandle<()>>,
+    base: String,
+}
+
+impl LfsServer {
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let thread = {
+            let server = Arc::clone(&server);
+            let state = Arc::clone(&state);
+            let base = base.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&base, &state, request);
+                }
+            })
+        };
+
+        LfsServer {
+            server,
+            state,
+            thread: Some(thread),
+            base,
+        }
+    }
+
+    /// The LFS endpoint, as configured with `lfs.url`.
+    pub fn url(&self) -> String {
+        format!("{}/repo.git/info/lfs", self.base)
+    }
+
+    /// Make `content` available for download.
+    pub fn add(&self, content: &[u8]) -> Pointer {
+        let pointer = Pointer::from_content(content);
+        let mut state = self.state.lock().unwrap();
+        state.objects.insert(pointer.oid.clone(), content.to_vec());
+        pointer
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for LfsServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle(base: &str, state: &Mutex<State>, mut request: tiny_http::Request) {
+    let url = request.url().to_string();
+    state
+        .lock()
+        .unwrap()
+        .requests
+        .push(format!("{} {}", request.method(), url));
+
+    let json_header = Header::from_bytes("Content-Type", "application/vnd.git-lfs+json").unwrap();
+    let response = match (request.method(), url.as_str()) {
+        (Method::Post, "/repo.git/info/lfs/objects/batch") => {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["operation"], "download");
+
+            let state = state.lock().unwrap();
+            let objects: Vec<Value> = body["objects"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|object| {
+                    let oid = object["oid"].as_str().unwrap();
+                    match state.objects.get(oid) {
+                        Some(content) => json!({
+                            "oid": oid,
+                            "size": content.len(),
+                            "actions": { "download": {
+                                "href": format!("{}/download/{}", base, oid),
+                                "header": { "Authorization": TOKEN },
+                            }},
+                        }),
+                        None => json!({
+                            "oid": oid,
+                            "size": object["size"],
+                            "error": { "code": 404, "message": "Object does not exist" },
+                        }),
+                    }
+                })
+                .collect();
+            let reply = json!({ "transfer": "basic", "objects": objects });
+            Response::from_string(reply.to_string()).with_header(json_header)
+        }
+        (Method::Get, path) if path.starts_with("/download/") => {
+            let authorized = request
+                .headers()
+                .iter()
+                .any(|h| h.field.equiv("Authorization") && h.value.as_str() == TOKEN);
+            let oid = &path["/download/".len()..];
+            match state.lock().unwrap().objects.get(oid) {
+                Some(content) if authorized => Response::from_data(content.clone()),
+                Some(_) => Response::from_string("unauthorized").with_status_code(401),
+                None => Response::from_string("not found").with_status_code(404),
+            }
+        }
+        _ => Response::from_string(r#"{"message":"Not found"}"#)
+            .with_status_code(404)
+            .with_header(json_header),
+    };
+    let _ = request.respond(response);
+}
@@ -0,0 +1,134 @@
+//! Tests for the `server` module against both the git CLI and this crate.
+//!
+//! This test target has no libtest harness: when invoked with `--serve` it
+//! runs as the long-running filter process itself.
+
+use git2::{FilterFlags, FilterList, FilterMode, Repository};
+use git2_process_filter::register_process_filter;
+use git2_process_filter::server::{self, FilterError, LongRunningFilter, Request};
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Uppercases on clean, lowercases on smudge, and delays every smudge it may.
+struct Upper;
+
+impl LongRunningFilter for Upper {
+    fn clean(&mut self, _request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+        Ok(input.to_ascii_uppercase())
+    }
+
+    fn smudge(&mut self, _request: &Request, input: &[u8]) -> Result<Vec<u8>, FilterError> {
+        Ok(input.to_ascii_lowercase())
+    }
+
+    fn supports_delay(&self) -> bool {
+        true
+    }
+
+    fn delay(&mut self, _request: &Request) -> bool {
+        true
+    }
+}
+
+fn main() {
+    if std::env::args().any(|arg| arg == "--serve") {
+        server::run(Upper).unwrap();
+        return;
+    }
+
+    let tests: &[(&str, fn())] = &[
+        ("test_server_with_git_cli", test_server_with_git_cli),
+        ("test_server_with_crate", test_server_with_crate),
+    ];
+    println!("\nrunning {} tests", tests.len());
+    for (name, test) in tests {
+        test();
+        println!("test {} ... ok", name);
+    }
+    println!("\ntest result: ok. {} passed\n", tests.len());
+}
+
+/// `filter.<name>.process` command that runs this binary as the filter.
+fn serve_cmd() -> String {
+    let exe = std::env::current_exe().unwrap();
+    format!("\"{}\" --serve", exe.display())
+}
+
+fn repo_init(filter_name: &str) -> (TempDir, Repository) {
+    let td = TempDir::new().unwrap();
+    let repo = Repository::init(td.path()).unwrap();
+    {
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test User").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        config
+            .set_str(&format!("filter.{}.process", filter_name), &serve_cmd())
+            .unwrap();
+        config
+            .set_bool(&format!("filter.{}.required", filter_name), true)
+            .unwrap();
+    }
+    fs::write(
+        td.path().join(".gitattributes"),
+        format!("*.txt filter={}\n", filter_name),
+    )
+    .unwrap();
+    (td, repo)
+}
+
+fn git(td: &TempDir, args: &[&str]) -> Vec<u8> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(td.path())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {:?}",
+        args,
+        output
+    );
+    output.stdout
+}
+
+/// git runs the server for `git add` and for a (delayed) checkout.
+fn test_server_with_git_cli() {
+    let (td, _repo) = repo_init("upper");
+    fs::write(td.path().join("a.txt"), "hello\n").unwrap();
+    fs::write(td.path().join("b.txt"), "world\n").unwrap();
+
+    git(&td, &["add", ".gitattributes", "a.txt", "b.txt"]);
+    assert_eq!(git(&td, &["show", ":a.txt"]), b"HELLO\n");
+    assert_eq!(git(&td, &["show", ":b.txt"]), b"WORLD\n");
+
+    fs::remove_file(td.path().join("a.txt")).unwrap();
+    fs::remove_file(td.path().join("b.txt")).unwrap();
+    git(&td, &["checkout", "--", "a.txt", "b.txt"]);
+    assert_eq!(fs::read(td.path().join("a.txt")).unwrap(), b"hello\n");
+    assert_eq!(fs::read(td.path().join("b.txt")).unwrap(), b"world\n");
+}
+
+/// This crate's client drives the server without delaying.
+fn test_server_with_crate() {
+    let filter_name = format!("upper_{}", std::process::id());
+    let (_td, repo) = repo_init(&filter_name);
+    let reg = register_process_filter(&repo, &filter_name).unwrap();
+
+    let caps = reg.process_capabilities().unwrap().unwrap();
+    assert!(caps.clean() && caps.smudge() && caps.delay());
+
+    for (mode, input, expected) in [
+        (FilterMode::ToOdb, &b"hello\n"[..], &b"HELLO\n"[..]),
+        (FilterMode::ToWorktree, &b"HELLO\n"[..], &b"hello\n"[..]),
+    ] {
+        let filter_list = FilterList::load(&repo, "a.txt", mode, FilterFlags::DEFAULT)
+            .unwrap()
+            .expect("Should have filter list");
+        let output = filter_list.apply_to_buffer(input).unwrap();
+        assert_eq!(output.as_ref(), expected);
+    }
+
+    let report = reg.shutdown();
+    assert!(report.success(), "{:?}", report);
+}